@@ -1,21 +1,77 @@
 use serde_derive::{Serialize, Deserialize};
 
-use crate::constants;
-use crate::math::Vec2;
+use crate::math::{LineSegment, Vec2};
+
+#[derive(Debug, PartialEq)]
+pub enum Crossing {
+    Forward,
+    Backward,
+}
+
+/// Checks whether moving from `from` to `to` crosses `gate`. Going forward
+/// means passing from the left to the right hand side of the gate, as seen
+/// on screen when looking from `p1` towards `p2`
+pub fn gate_crossing(gate: &LineSegment, from: Vec2, to: Vec2) -> Option<Crossing> {
+    if !gate.intersects(LineSegment::new(from, to)) {
+        return None;
+    }
+    let before = gate.side(from);
+    let after = gate.side(to);
+    if before <= 0. && after > 0. {
+        Some(Crossing::Forward)
+    }
+    else if before > 0. && after <= 0. {
+        Some(Crossing::Backward)
+    }
+    else {
+        None
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Checkpoint {
-    pub position: Vec2,
+    pub gate: LineSegment,
 }
 
 impl Checkpoint {
-    pub fn new(position: Vec2) -> Checkpoint {
+    pub fn new(gate: LineSegment) -> Checkpoint {
         Checkpoint {
-            position
+            gate
+        }
+    }
+
+    pub fn crossing(&self, from: Vec2, to: Vec2) -> Option<Crossing> {
+        gate_crossing(&self.gate, from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::vec2;
+
+    fn gate() -> Checkpoint {
+        // Vertical gate which is passed forward when driving towards positive x
+        Checkpoint::new(LineSegment::new(vec2(2400., 3564.), vec2(2400., 2364.)))
+    }
+
+    #[test]
+    fn gates_are_crossed_forward_in_their_direction() {
+        let gate = gate();
+        for i in 0..1000 {
+            let y = 2365. + i as f32 * 1.199;
+            let from = vec2(2399.7 - (i % 7) as f32 * 0.31, y);
+            let to = vec2(2400.3 + (i % 5) as f32 * 0.47, y + 0.13);
+            assert_eq!(gate.crossing(from, to), Some(Crossing::Forward), "{:?} -> {:?}", from, to);
+            assert_eq!(gate.crossing(to, from), Some(Crossing::Backward), "{:?} -> {:?}", to, from);
         }
     }
 
-    pub fn player_reached(&self, player_position: Vec2) -> bool {
-        player_position.distance_to(self.position) < constants::CHECKPOINT_RADIUS * constants::MAP_SCALE
+    #[test]
+    fn gates_are_not_crossed_around_their_ends() {
+        let gate = gate();
+        assert_eq!(gate.crossing(vec2(2390., 2300.), vec2(2410., 2300.)), None);
+        assert_eq!(gate.crossing(vec2(2390., 3600.), vec2(2410., 3600.)), None);
+        assert_eq!(gate.crossing(vec2(2390., 3000.), vec2(2399., 3000.)), None);
     }
 }
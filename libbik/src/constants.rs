@@ -1,9 +1,43 @@
-pub const TOTAL_NUM_LAPS: usize = 3;
-
 // currently hardcoded to the background image size
 pub const WORLD_SIZE: f32 = 3000.;
 pub const DELTA_TIME: f32 = 0.01;
-pub const SERVER_SLEEP_DURATION: u64 = 10;
+// Most simulation steps the server takes at once to catch up after falling behind
+pub const MAX_CATCH_UP_STEPS: u32 = 10;
+// Milliseconds the network thread waits before retrying writes that did not
+// go through, while there are any
+pub const NETWORK_RETRY_INTERVAL: u64 = 5;
+// Largest message payload in bytes, peers sending anything larger are dropped
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+// Largest possible UDP payload
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+// Queued outgoing bytes after which superseded unreliable messages are dropped
+pub const OUTBOUND_HIGH_WATER_MARK: usize = 64 * 1024;
+// Queued outgoing bytes after which the peer is considered dead
+pub const OUTBOUND_LIMIT: usize = 4 * MAX_FRAME_SIZE;
+// Seconds between resends of unacknowledged reliable UDP messages
+pub const UDP_RESEND_INTERVAL: f32 = 0.1;
+pub const UDP_CONNECT_TIMEOUT: f32 = 5.;
+// Clients which have not said hello within this many seconds are dropped
+pub const HANDSHAKE_TIMEOUT: f32 = 5.;
+// Seconds a closed connection is kept around to send what was queued for it
+pub const CLOSE_FLUSH_TIMEOUT: f32 = 1.;
+// Seconds between pings sent to each client
+pub const PING_INTERVAL: f32 = 1.;
+// Default number of seconds without any message before a client is dropped
+pub const IDLE_TIMEOUT: f32 = 10.;
+// Seconds without any message before the client gives up on the server
+pub const SERVER_TIMEOUT: f32 = 10.;
+// Seconds the player of a lost connection is kept around for it to reconnect
+pub const RECONNECT_GRACE_PERIOD: f32 = 60.;
+// Inputs the server buffers per client before it starts dropping the oldest
+pub const MAX_QUEUED_INPUTS: usize = 10;
+
+// Other players are drawn this many seconds in the past
+pub const INTERPOLATION_DELAY: f32 = 0.1;
+pub const MAX_EXTRAPOLATION: f32 = 0.25;
+pub const SNAPSHOT_BUFFER_SIZE: usize = 32;
+// Number of snapshots kept around as possible bases for delta compression
+pub const SNAPSHOT_HISTORY_LENGTH: usize = 64;
 
 pub const WINDOW_SIZE: f32 = 700.;
 pub const PIXEL_SCALE: u32 = 4;
@@ -35,6 +69,21 @@ pub const TIME_PADDING: f32 = 20.;
 pub const TIME_COLOR: (u8, u8, u8) = (10, 10, 10);
 pub const BEST_TIME_COLOR: (u8, u8, u8) = (0, 150, 0);
 pub const FINAL_LAP_COLOR: (u8, u8, u8) = (0, 150, 0);
+pub const WRONG_WAY_COLOR: (u8, u8, u8) = (200, 0, 0);
+pub const WRONG_WAY_POS_Y: f32 = 0.3;
+pub const STANDING_POS_X: f32 = 0.9;
+pub const STANDING_POS_Y: f32 = 0.1;
+// Distance between the lines with gaps to the players ahead and behind
+pub const GAP_PADDING: f32 = 20.;
+pub const GAP_COLOR: (u8, u8, u8) = (10, 10, 10);
+pub const KNOCKED_OUT_COLOR: (u8, u8, u8) = (200, 0, 0);
+// Seconds a killfeed message is shown, it fades out during the last KILLFEED_FADE_TIME
+pub const KILLFEED_DURATION: f32 = 5.;
+pub const KILLFEED_FADE_TIME: f32 = 1.;
+pub const KILLFEED_POS_X: f32 = 0.8;
+pub const KILLFEED_POS_Y: f32 = 0.3;
+pub const KILLFEED_PADDING: f32 = 20.;
+pub const KILLFEED_COLOR: (u8, u8, u8) = (255, 255, 255);
 pub const END_SCREEN_COLOR: (u8, u8, u8, u8) = (0, 0, 0, 128);
 pub const END_SCREEN_PADDING: f32 = 0.1;
 pub const DEFAULT_FINISH_COLOR: (u8, u8, u8) = (255, 255, 255);
@@ -50,19 +99,43 @@ pub const MAX_FUEL_LEVEL: f32 = 100.;
 pub const FUEL_CONSUMPTION: f32 = 5.;
 pub const FUEL_PUMP_SPEED: f32 = 75.;
 
+pub const MAX_HEALTH: f32 = 100.;
+// Health restored per second at pit stops
+pub const REPAIR_SPEED: f32 = 25.;
+
 // powerup parameters
 pub const POWERUP_DISTANCE: f32 = 100.;
 pub const POWERUP_TIMEOUT: f32 = 5.;
 
 pub const MACE_SPEED: f32 = 10.;
 pub const MACE_RADIUS: f32 = 100.;
-pub const MACE_DAMAGE: i16 = 50;
+pub const MACE_DAMAGE: f32 = 50.;
 
 pub const NITRO_SPEED_FACTOR: f32 = 1.5;
 
 // Map parameters
+// Every map is a folder in here containing map.ron, track.png and its overlays
+pub const MAPS_DIRECTORY: &str = "resources/maps";
+// Generated maps use the terrain of this map
+pub const PALETTE_MAP: &str = "default";
 pub const MAP_SCALE: f32 = 2.;
-pub const CHECKPOINT_RADIUS: f32 = 300.;
+// Distance between players who do not fit on the start grid and are lined up behind it
+pub const START_GRID_SPACING: f32 = 50.;
+// Seconds between checks for changes to the files of the current map
+pub const MAP_RELOAD_CHECK_INTERVAL: f32 = 1.;
+// Largest RGB distance from a palette color for a ground pixel to count as that terrain
+pub const MAX_TERRAIN_COLOR_DISTANCE: u32 = 40;
+
+// Map editor parameters
+// Distance in screen pixels within which things can be grabbed with the mouse
+pub const EDITOR_HANDLE_RADIUS: f32 = 12.;
+// Screen pixels per second the view moves when panning with the arrow keys
+pub const EDITOR_PAN_SPEED: f32 = 800.;
+pub const EDITOR_ZOOM_STEP: f32 = 1.2;
+pub const EDITOR_MIN_ZOOM: f32 = 0.1;
+pub const EDITOR_MAX_ZOOM: f32 = 4.;
+// Length of newly placed checkpoint gates, in map coordinates
+pub const EDITOR_GATE_LENGTH: f32 = 200.;
 
 // UI parameters
 pub const GAUGE_BACKGROUND: (u8, u8, u8) = (10, 10, 10);
@@ -71,6 +144,8 @@ pub const GAUGE_POS_Y: f32 = 0.5;
 pub const GAUGE_HEIGHT: f32 = 0.47;
 pub const GAUGE_WIDTH: f32 = 0.05;
 pub const GAUGE_TEXT_POS_PADDING: f32 = 0.023;
+pub const HEALTH_GAUGE_POS_X: f32 = 0.1;
+pub const HEALTH_BAR_COLOR: (u8, u8, u8) = (200, 30, 30);
 
 pub const RACE_COUNTDOWN_TIMER_START: f32 = 3.;
 
@@ -80,11 +155,17 @@ pub const COUNTDOWN_TEXT_MAX_SIZE: f32 = 3.;
 pub const COUNTDOWN_TEXT_MIN_SIZE: f32 = 2.;
 
 pub const COLLISION_GRACE_PERIOD: f32 = 4.;
-pub const COLLISION_DAMAGE: i16 = 10;
+// Damage of a collision between bikes at a relative speed of DAMAGE_REFERENCE_SPEED
+pub const COLLISION_DAMAGE: f32 = 10.;
+// Damage of driving into a static object at DAMAGE_REFERENCE_SPEED
+pub const OBJECT_DAMAGE: f32 = 15.;
+pub const DAMAGE_REFERENCE_SPEED: f32 = 400.;
 pub const COLLISION_SPEED_REDUCTION: f32 = 500.;
 
 
 // Crash parameters
 pub const FALLING_DURATION: f32 = 0.2;
 pub const CRASH_DURATION: f32 = 1.5;
+// Time spent on the ground after a crash which took the last health
+pub const KNOCKED_OUT_DURATION: f32 = 5.;
 pub const FALLING_STAGES: usize = 2;
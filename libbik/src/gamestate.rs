@@ -1,15 +1,18 @@
 use std::sync::mpsc::Receiver;
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 
 use serde_derive::{Serialize, Deserialize};
 
 use crate::checkpoint::Checkpoint;
 use crate::constants;
+use crate::killfeed::KillFeed;
 use crate::math::{Vec2, vec2, LineSegment};
-use crate::player::{PlayerState, Player};
+use crate::player::{CrashCause, PlayerState, Player};
 use crate::powerup::{Powerup, PowerupKind};
+use crate::snapshot::{Snapshot, StaticState};
 use crate::static_object::StaticObject;
-use crate::track;
+use crate::track::{self, StartSlot};
 use crate::weapon;
 use crate::messages::SoundEffect;
 
@@ -21,41 +24,98 @@ pub enum RaceState {
     Finished,
 }
 
+/// Something that happened during a race, for building statistics
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum GameEvent {
+    Crash { player: u64, cause: CrashCause },
+}
+
+/// A player's place in the race
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Standing {
+    pub id: u64,
+    /// Seconds behind the player one place ahead, measured at the last gate
+    /// both have passed. `None` for the leader
+    pub gap_ahead: Option<f32>,
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct GameState {
     pub players: Vec<Player>,
     pub powerups: Vec<Powerup>,
     pub checkpoints: Vec<Checkpoint>,
-    pub start_position: Vec2,
+    /// Places of the players at the start, only known by the server
+    pub start_grid: Vec<StartSlot>,
+    pub finish_line: LineSegment,
     pub race_state: RaceState,
     pub static_objects: Vec<StaticObject>,
     pub finished_players: Vec<u64>,
+    /// All players ordered by their place in the race
+    pub standings: Vec<Standing>,
+    /// Announcements of crashes, fastest laps and finishes
+    pub killfeed: KillFeed,
+    /// Best lap time driven in the race so far, only known by the server
+    pub fastest_lap: f32,
+    /// Events which have not been taken with `take_events` yet, only known
+    /// by the server
+    pub events: Vec<GameEvent>,
+    /// Race time when each player first passed each gate, indexed by
+    /// `progress`. Only known by the server
+    pub split_times: HashMap<u64, Vec<f32>>,
+    /// Number of laps in the race
+    pub laps: usize,
+    /// Seconds after the start when the race is ended, only known by the server
+    pub time_limit: Option<f32>,
+    /// Seconds since the race started, only known by the server
+    pub race_time: f32,
+    /// Number of simulation steps taken since the server started
+    pub tick: u64,
 }
 
 impl GameState {
     pub fn new(
         mut powerups: Vec<Powerup>,
-        start_point: Vec2,
-        checkpoint_positions: &Vec<Vec2>,
-        static_objects: Vec<StaticObject>
+        mut start_grid: Vec<StartSlot>,
+        finish_line: LineSegment,
+        checkpoint_gates: &[LineSegment],
+        static_objects: Vec<StaticObject>,
+        laps: usize,
+        time_limit: Option<f32>,
     ) -> GameState {
         for p in &mut powerups {
             p.position *= constants::MAP_SCALE;
         }
+        for slot in &mut start_grid {
+            slot.position *= constants::MAP_SCALE;
+        }
+        let scale = |line: &LineSegment| LineSegment::new(
+            line.p1 * constants::MAP_SCALE,
+            line.p2 * constants::MAP_SCALE,
+        );
+        let finish_line = scale(&finish_line);
 
-        let checkpoints = checkpoint_positions.iter().cloned().map(|pos|
-            Checkpoint::new(pos * constants::MAP_SCALE)
+        let checkpoints = checkpoint_gates.iter().map(|gate|
+            Checkpoint::new(scale(gate))
         ).collect();
 
         GameState {
             players: Vec::new(),
             powerups,
             checkpoints,
-            start_position: start_point,
+            start_grid,
+            finish_line,
             race_state: RaceState::NotStarted,
             static_objects,
             finished_players: Vec::new(),
+            standings: Vec::new(),
+            killfeed: KillFeed::new(),
+            fastest_lap: f32::INFINITY,
+            events: Vec::new(),
+            split_times: HashMap::new(),
+            laps,
+            time_limit,
+            race_time: 0.,
+            tick: 0,
         }
     }
 
@@ -68,6 +128,8 @@ impl GameState {
      *  )
      */
     pub fn update(&mut self, delta: f32, mut add_sound: impl FnMut((SoundEffect, Vec2))) {
+        self.tick += 1;
+        self.killfeed.manage_killfeed(self.tick);
         self.race_state = match self.race_state {
             RaceState::Starting(time) => {
                 if time - delta < 0. {
@@ -77,6 +139,8 @@ impl GameState {
                 }
             }
             RaceState::Started => {
+                self.race_time += delta;
+
                 // update game state
                 self.handle_player_collisions(&mut add_sound);
                 self.handle_object_collision(&mut add_sound);
@@ -84,9 +148,15 @@ impl GameState {
 
                 self.update_powerups(delta, &mut add_sound);
 
+                self.announce_fastest_lap();
                 let all_finished = self.update_finished_players();
+                self.update_standings();
+
+                let out_of_time = self.time_limit
+                    .map(|limit| self.race_time >= limit)
+                    .unwrap_or(false);
 
-                if all_finished {
+                if all_finished || out_of_time {
                     RaceState::Finished
                 } else {
                     RaceState::Started
@@ -96,6 +166,42 @@ impl GameState {
         };
     }
 
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            players: self.players.clone(),
+            powerups: self.powerups.clone(),
+            race_state: self.race_state.clone(),
+            finished_players: self.finished_players.clone(),
+            standings: self.standings.clone(),
+        }
+    }
+
+    pub fn apply_snapshot(&mut self, snapshot: Snapshot) {
+        self.players = snapshot.players;
+        self.powerups = snapshot.powerups;
+        self.race_state = snapshot.race_state;
+        self.finished_players = snapshot.finished_players;
+        self.standings = snapshot.standings;
+    }
+
+    pub fn static_state(&self, map: &str, map_revision: u64) -> StaticState {
+        StaticState {
+            map: map.to_string(),
+            map_revision,
+            laps: self.laps,
+            checkpoints: self.checkpoints.clone(),
+            finish_line: self.finish_line.clone(),
+            static_objects: self.static_objects.clone(),
+        }
+    }
+
+    pub fn apply_static_state(&mut self, static_state: StaticState) {
+        self.laps = static_state.laps;
+        self.checkpoints = static_state.checkpoints;
+        self.finish_line = static_state.finish_line;
+        self.static_objects = static_state.static_objects;
+    }
+
     pub fn get_player_finish_position(&self, player_id: u64) -> i32 {
         let mut position = 1;
         for id in &self.finished_players {
@@ -107,6 +213,14 @@ impl GameState {
         -1
     }
 
+    /// Place of the player in the race starting at 1, and the number of
+    /// players in it
+    pub fn get_player_standing(&self, player_id: u64) -> Option<(usize, usize)> {
+        self.standings.iter()
+            .position(|standing| standing.id == player_id)
+            .map(|index| (index + 1, self.standings.len()))
+    }
+
     pub fn update_powerups(&mut self, delta: f32, mut add_sound: impl FnMut((SoundEffect, Vec2))) {
         let mut i = 0;
         'powerups: loop {
@@ -137,10 +251,32 @@ impl GameState {
         }
     }
 
+    /// The place of the player with the given index at the start. Players who
+    /// do not fit on the grid are lined up behind its last slot
+    pub fn start_slot(&self, index: usize) -> StartSlot {
+        if let Some(slot) = self.start_grid.get(index) {
+            return slot.clone();
+        }
+        let last = self.start_grid.last().cloned().unwrap_or(StartSlot {
+            position: self.finish_line.midpoint(),
+            angle: 0.,
+        });
+        let distance = (index + 1 - self.start_grid.len()) as f32 * constants::START_GRID_SPACING;
+        StartSlot {
+            position: last.position - Vec2::from_direction(last.angle, distance),
+            angle: last.angle,
+        }
+    }
+
     pub fn add_player(&mut self, player: Player) {
         self.players.push(player);
     }
 
+    /// Returns the events since the last call
+    pub fn take_events(&mut self) -> Vec<GameEvent> {
+        std::mem::take(&mut self.events)
+    }
+
     pub fn get_player_by_id(&self, id: u64) -> Option<&Player> {
         for player in &self.players {
             if player.id == id {
@@ -152,33 +288,42 @@ impl GameState {
     }
 
     fn handle_player_attacks(&mut self, mut add_sound: impl FnMut((SoundEffect, Vec2))) {
-        let mace_positions: Vec<Vec2> = self
+        let mace_positions: Vec<(u64, Vec2)> = self
             .players
             .iter()
             .filter_map(|player| {
                 match &player.weapon {
                     Some(weapon::Weapon::Mace(mace)) => Some({
                         let offset = Vec2::from_direction(mace.angle, constants::MACE_RADIUS);
-                        offset + player.position
+                        (player.id, offset + player.position)
                     }),
                     _ => None,
                 }
             })
             .collect();
 
-        for mace in mace_positions {
+        let mut crashes = vec!();
+        for (attacker, mace) in mace_positions {
             for target in &mut self.players {
+                if target.id == attacker {
+                    continue;
+                }
                 for (c1, r1) in target.collision_points() {
-                    if c1.distance_to(mace) < r1 {
-                        target.crash(&mut add_sound);
+                    let cause = CrashCause::Mace { attacker };
+                    if c1.distance_to(mace) < r1 && target.crash(constants::MACE_DAMAGE, cause.clone(), &mut add_sound) {
+                        crashes.push((target.id, cause));
                     }
                 }
             }
         }
+        for (player, cause) in crashes {
+            self.record_crash(player, cause);
+        }
     }
 
     pub fn handle_player_collisions(&mut self, mut add_sound: impl FnMut((SoundEffect, Vec2))) {
-        let mut collided_players = HashSet::new();
+        // Damage and cause of the hardest collision of each player
+        let mut damages: HashMap<u64, (f32, CrashCause)> = HashMap::new();
 
         if !self.players.is_empty() {
             for (i, p1) in self.players[..self.players.len() - 1].iter().enumerate() {
@@ -187,8 +332,20 @@ impl GameState {
                         for (c2, r2) in p2.collision_points() {
                             let distance = (c1 - c2).norm();
                             if p1.id != p2.id && distance < r1+r2 as f32 {
-                                collided_players.insert(p1.id);
-                                collided_players.insert(p2.id);
+                                let relative_speed = (p1.velocity - p2.velocity).norm();
+                                let damage = constants::COLLISION_DAMAGE * relative_speed
+                                    / constants::DAMAGE_REFERENCE_SPEED;
+                                let p1_faster = p1.velocity.norm() > p2.velocity.norm();
+                                let hits = [
+                                    (p1.id, CrashCause::Collision { other: p2.id, rammed: !p1_faster }),
+                                    (p2.id, CrashCause::Collision { other: p1.id, rammed: p1_faster }),
+                                ];
+                                for (id, cause) in hits.iter().cloned() {
+                                    let old = damages.entry(id).or_insert((damage, cause.clone()));
+                                    if damage > old.0 {
+                                        *old = (damage, cause);
+                                    }
+                                }
                             }
                         }
                     }
@@ -196,9 +353,48 @@ impl GameState {
             }
         }
 
+        let mut crashes = vec!();
         for player in &mut self.players {
-            if collided_players.contains(&player.id) {
-                player.crash(&mut add_sound);
+            if let Some((damage, cause)) = damages.remove(&player.id) {
+                if player.crash(damage, cause.clone(), &mut add_sound) {
+                    crashes.push((player.id, cause));
+                }
+            }
+        }
+        for (player, cause) in crashes {
+            self.record_crash(player, cause);
+        }
+    }
+
+    /// Emits the event for a crash, and announces it if it was caused by
+    /// another player
+    fn record_crash(&mut self, player: u64, cause: CrashCause) {
+        let name = |id| self.get_player_by_id(id)
+            .map(|player| player.name.clone())
+            .unwrap_or_default();
+        let message = match &cause {
+            CrashCause::Mace { attacker } =>
+                Some(format!("{} smacked {}", name(*attacker), name(player))),
+            CrashCause::Collision { other, rammed: true } =>
+                Some(format!("{} rammed {}", name(*other), name(player))),
+            _ => None,
+        };
+        if let Some(message) = message {
+            self.killfeed.add_message(&message, self.tick);
+        }
+        self.events.push(GameEvent::Crash { player, cause });
+    }
+
+    /// Announces players who drove a lap faster than anyone before them in
+    /// the race
+    fn announce_fastest_lap(&mut self) {
+        for player in &self.players {
+            if player.best_lap < self.fastest_lap {
+                self.fastest_lap = player.best_lap;
+                self.killfeed.add_message(
+                    &format!("{} set the fastest lap: {:.2}s", player.name, player.best_lap),
+                    self.tick,
+                );
             }
         }
     }
@@ -214,13 +410,70 @@ impl GameState {
                 all_finished = false;
             } else if !self.finished_players.contains(&player.id) {
                 self.finished_players.push(player.id);
+                let message = match self.finished_players.len() {
+                    1 => format!("{} won the race!", player.name),
+                    position => format!("{} finished in {}:th position", player.name, position),
+                };
+                self.killfeed.add_message(&message, self.tick);
             }
         }
 
         all_finished
     }
 
+    /// Number of gates the player has passed since the start
+    fn progress(&self, player: &Player) -> usize {
+        player.lap * (self.checkpoints.len() + 1) + player.checkpoint
+    }
+
+    /**
+     * Records split times and ranks the players. Finished players are ranked
+     * by finish order, the rest by passed gates and then by distance to the
+     * next one.
+     */
+    fn update_standings(&mut self) {
+        for player in &self.players {
+            let progress = self.progress(player);
+            let splits = self.split_times.entry(player.id).or_default();
+            while splits.len() <= progress {
+                splits.push(self.race_time);
+            }
+        }
+
+        let finish_position = |player: &Player| self.finished_players.iter()
+            .position(|id| *id == player.id)
+            .unwrap_or(usize::MAX);
+        let mut order: Vec<&Player> = self.players.iter().collect();
+        order.sort_by(|a, b| {
+            finish_position(a).cmp(&finish_position(b))
+                .then_with(|| self.progress(b).cmp(&self.progress(a)))
+                .then_with(|| {
+                    let distance_a = self.vector_to_checkpoint(a).norm();
+                    let distance_b = self.vector_to_checkpoint(b).norm();
+                    distance_a.partial_cmp(&distance_b).unwrap_or(Ordering::Equal)
+                })
+        });
+
+        let mut standings = vec!();
+        for (place, player) in order.iter().enumerate() {
+            let gap_ahead = place.checked_sub(1).map(|ahead_place| {
+                let ahead = &self.split_times[&order[ahead_place].id];
+                let behind = &self.split_times[&player.id];
+                // Players who drove backwards have been past later gates
+                // than their progress says, those splits do not count
+                let gate = self.progress(player).min(behind.len() - 1);
+                match ahead.get(gate) {
+                    Some(ahead_time) => (behind[gate] - ahead_time).max(0.),
+                    None => 0.,
+                }
+            });
+            standings.push(Standing { id: player.id, gap_ahead });
+        }
+        self.standings = standings;
+    }
+
     pub fn handle_object_collision(&mut self, mut add_sound: impl FnMut((SoundEffect, Vec2))) {
+        let mut crashes = vec!();
         for player in &mut self.players {
             if player.state != PlayerState::Upright || player.velocity.norm() < constants::MIN_CRASH_VELOCITY {
                 break;
@@ -230,20 +483,28 @@ impl GameState {
                     if let Some(obj_radius) = object.collision_radius() {
                         let distance = (c - object.position * constants::MAP_SCALE).norm();
                         if distance < r + obj_radius * constants::STATIC_OBJECT_SCALE {
-                            player.crash(&mut add_sound);
+                            let damage = constants::OBJECT_DAMAGE * player.velocity.norm()
+                                / constants::DAMAGE_REFERENCE_SPEED;
+                            let cause = CrashCause::StaticObject(object.kind);
+                            if player.crash(damage, cause.clone(), &mut add_sound) {
+                                crashes.push((player.id, cause));
+                            }
                         }
                     }
                 }
             }
         }
+        for (player, cause) in crashes {
+            self.record_crash(player, cause);
+        }
     }
 
     pub fn vector_to_checkpoint(&self, player: &Player) -> Vec2 {
         let checkpoint_pos = if player.checkpoint < self.checkpoints.len() {
-            self.checkpoints[player.checkpoint].position
+            self.checkpoints[player.checkpoint].gate.midpoint()
         }
         else {
-            self.start_position
+            self.finish_line.midpoint()
         };
 
         checkpoint_pos - player.position
@@ -252,6 +513,51 @@ impl GameState {
 
 impl Default for GameState {
     fn default() -> Self {
-        Self::new(Vec::new(), vec2(0., 0.), &Vec::new(), Vec::new())
+        Self::new(
+            Vec::new(),
+            Vec::new(),
+            LineSegment::new(vec2(0., 0.), vec2(0., 0.)),
+            &Vec::new(),
+            Vec::new(),
+            0,
+            None,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crashes_are_emitted_as_events() {
+        let mut state = GameState::new(
+            vec!(),
+            vec!(),
+            LineSegment::new(vec2(0., 0.), vec2(0., 10.)),
+            &[],
+            vec!(),
+            1,
+            None,
+        );
+        for (id, position, velocity) in &[(1, vec2(0., 0.), vec2(500., 0.)), (2, vec2(5., 0.), vec2(0., 0.))] {
+            let mut player = Player::new(*id, format!("player {}", id), *position, 0.);
+            player.velocity = *velocity;
+            player.time_to_next_collision = 0.;
+            state.add_player(player);
+        }
+
+        state.handle_player_collisions(|_| {});
+
+        let events = state.take_events();
+        assert!(events.contains(&GameEvent::Crash {
+            player: 2,
+            cause: CrashCause::Collision { other: 1, rammed: true },
+        }));
+        assert!(events.contains(&GameEvent::Crash {
+            player: 1,
+            cause: CrashCause::Collision { other: 2, rammed: false },
+        }));
+        assert!(state.take_events().is_empty());
     }
 }
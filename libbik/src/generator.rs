@@ -0,0 +1,301 @@
+use std::f32::consts::PI;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::ground::{Color, Terrain, TerrainType};
+use crate::math::{vec2, LineSegment, Vec2};
+use crate::powerup::{Powerup, PowerupKind, Weapon};
+use crate::static_object::{StaticObject, StaticObjectKind};
+use crate::constants;
+use crate::track::{self, MapConfig, StartSlot};
+
+const MAP_NAME_PREFIX: &str = "generated-";
+/// Size of the ground image, which is also the size of the map in map coordinates
+const SIZE: u32 = 2048;
+const ROAD_HALF_WIDTH: f32 = 60.;
+/// Distance along the track between points of the center line
+const STEP_LENGTH: f32 = 4.;
+const CONTROL_POINTS: usize = 12;
+const CHECKPOINTS: usize = 5;
+const TREES: usize = 60;
+const TIRES: usize = 12;
+const PUDDLES: usize = 4;
+
+/// Color of the first terrain of `kind` in the palette
+fn color_of(terrain: &[Terrain], kind: TerrainType) -> Result<Color, String> {
+    terrain.iter()
+        .find(|terrain| terrain.kind == kind)
+        .map(|terrain| terrain.color)
+        .ok_or_else(|| format!("The palette has no {:?} terrain", kind))
+}
+
+pub fn map_name(seed: u64) -> String {
+    format!("{}{}", MAP_NAME_PREFIX, seed)
+}
+
+/// The seed of a map name created by `map_name`
+pub fn seed_from_map_name(name: &str) -> Option<u64> {
+    name.strip_prefix(MAP_NAME_PREFIX)?.parse().ok()
+}
+
+/// A generated map, ready to be written to a map folder
+pub struct GeneratedMap {
+    pub config: MapConfig,
+    /// RGB pixels of the ground image
+    pub pixels: Vec<u8>,
+}
+
+/// Point on a Catmull-Rom spline through `p1` and `p2` at `t` between 0 and 1
+fn catmull_rom(p0: Vec2, p1: Vec2, p2: Vec2, p3: Vec2, t: f32) -> Vec2 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    (p1 * 2.
+        + (p2 - p0) * t
+        + (p0 * 2. - p1 * 5. + p2 * 4. - p3) * t2
+        + (p1 * 3. - p0 - p2 * 3. + p3) * t3) * 0.5
+}
+
+/// Points along the center line of a closed loop around the middle of the
+/// map, roughly `STEP_LENGTH` apart
+fn center_line(rng: &mut StdRng) -> Vec<Vec2> {
+    let middle = vec2(SIZE as f32, SIZE as f32) / 2.;
+    let controls = (0..CONTROL_POINTS)
+        .map(|i| {
+            let jitter = rng.gen_range(-0.3, 0.3);
+            let angle = (i as f32 + jitter) / CONTROL_POINTS as f32 * 2. * PI;
+            middle + Vec2::from_direction(angle, rng.gen_range(450., 850.))
+        })
+        .collect::<Vec<_>>();
+
+    let mut points = vec!();
+    for i in 0..CONTROL_POINTS {
+        let control = |offset: usize| controls[(i + offset) % CONTROL_POINTS];
+        let (p0, p1, p2, p3) = (control(CONTROL_POINTS - 1), control(0), control(1), control(2));
+        let steps = (p1.distance_to(p2) / STEP_LENGTH).ceil() as usize;
+        for step in 0..steps {
+            points.push(catmull_rom(p0, p1, p2, p3, step as f32 / steps as f32));
+        }
+    }
+    points
+}
+
+/// Direction of travel at a point of the center line
+fn tangent(line: &[Vec2], i: usize) -> Vec2 {
+    (line[(i + 1) % line.len()] - line[(i + line.len() - 1) % line.len()]).normalize()
+}
+
+/// A gate across the road which is passed forward when going along the line
+fn gate(line: &[Vec2], i: usize) -> LineSegment {
+    let direction = tangent(line, i);
+    let across = vec2(direction.y, -direction.x) * (ROAD_HALF_WIDTH + 20.);
+    LineSegment::new(line[i] - across, line[i] + across)
+}
+
+fn distance_to_line(line: &[Vec2], point: Vec2) -> f32 {
+    line.iter()
+        .map(|p| p.distance_to(point))
+        .fold(f32::INFINITY, f32::min)
+}
+
+fn paint_disc(pixels: &mut [u8], center: Vec2, radius: f32, color: Color, only_on: Option<Color>) {
+    let min_x = (center.x - radius).max(0.) as u32;
+    let min_y = (center.y - radius).max(0.) as u32;
+    let max_x = ((center.x + radius) as u32).min(SIZE - 1);
+    let max_y = ((center.y + radius) as u32).min(SIZE - 1);
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            if vec2(x as f32, y as f32).distance_to(center) > radius {
+                continue;
+            }
+            let i = (y * SIZE + x) as usize * 3;
+            let current = (pixels[i], pixels[i + 1], pixels[i + 2]);
+            if only_on.map(|color| color == current).unwrap_or(true) {
+                pixels[i..i + 3].copy_from_slice(&[color.0, color.1, color.2]);
+            }
+        }
+    }
+}
+
+/// Generates a closed loop track. The same seed always gives the same map
+/// with the same build, but the random number generator is not guaranteed
+/// to stay the same across versions of `rand`. The ground is painted with
+/// the colors of `terrain`, which becomes the palette of the map
+pub fn generate(seed: u64, terrain: Vec<Terrain>) -> Result<GeneratedMap, String> {
+    let road = color_of(&terrain, TerrainType::Road)?;
+    let puddle = color_of(&terrain, TerrainType::Puddle)?;
+    let sand = color_of(&terrain, TerrainType::Sand)?;
+    let pit_stop = color_of(&terrain, TerrainType::PitStop)?;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut line = center_line(&mut rng);
+    // The finish line image is drawn across a road going to the right, so
+    // the loop starts where the track is closest to doing that
+    let start = (0..line.len())
+        .max_by(|a, b| tangent(&line, *a).x.partial_cmp(&tangent(&line, *b).x).unwrap())
+        .unwrap();
+    line.rotate_left(start);
+
+    let mut pixels = [sand.0, sand.1, sand.2].repeat((SIZE * SIZE) as usize);
+    for point in &line {
+        paint_disc(&mut pixels, *point, ROAD_HALF_WIDTH, road, None);
+    }
+    for _ in 0..PUDDLES {
+        let center = line[rng.gen_range(0, line.len())];
+        paint_disc(&mut pixels, center, rng.gen_range(20., 40.), puddle, Some(road));
+    }
+    // The pit stop is at the side of the road a bit after the start
+    let pit_index = line.len() / (CHECKPOINTS + 1) / 2;
+    let pit_side = vec2(tangent(&line, pit_index).y, -tangent(&line, pit_index).x);
+    for point in &line[pit_index..pit_index + 20] {
+        paint_disc(&mut pixels, *point + pit_side * (ROAD_HALF_WIDTH - 20.), 20., pit_stop, Some(road));
+    }
+
+    let start_direction = tangent(&line, 0);
+    let start_grid = (0..4)
+        .map(|i| {
+            let side = vec2(start_direction.y, -start_direction.x) * if i % 2 == 0 { 20. } else { -20. };
+            StartSlot {
+                position: line[0] - start_direction * (20. + 25. * i as f32) + side,
+                angle: start_direction.angle(),
+            }
+        })
+        .collect();
+    let checkpoints = (1..=CHECKPOINTS)
+        .map(|i| gate(&line, i * line.len() / (CHECKPOINTS + 1)))
+        .collect();
+
+    let powerups = (0..3)
+        .map(|i| Powerup {
+            position: line[(2 * i + 1) * line.len() / 6],
+            kind: if i == 1 { PowerupKind::Weapon(Weapon::Mace) } else { PowerupKind::Nitro(3.) },
+            timeout: 0.,
+        })
+        .collect();
+
+    // Trees stay off the road and tires line its edge, neither may touch
+    // anything placed before
+    let mut static_objects: Vec<StaticObject> = vec!();
+    let place = |object: StaticObject, static_objects: &mut Vec<StaticObject>| {
+        let free = static_objects.iter()
+            .all(|other| other.position.distance_to(object.position) > 40.);
+        if free && distance_to_line(&line, object.position) > ROAD_HALF_WIDTH + 15. {
+            static_objects.push(object);
+        }
+    };
+    for _ in 0..TREES {
+        let position = vec2(rng.gen_range(0., SIZE as f32), rng.gen_range(0., SIZE as f32));
+        let variant = rng.gen_range(0, StaticObjectKind::Tree.variant_count());
+        place(StaticObject { position, kind: StaticObjectKind::Tree, variant }, &mut static_objects);
+    }
+    for _ in 0..TIRES {
+        let i = rng.gen_range(0, line.len());
+        let side = if rng.gen() { 1. } else { -1. };
+        let outwards = vec2(tangent(&line, i).y, -tangent(&line, i).x) * side;
+        let position = line[i] + outwards * (ROAD_HALF_WIDTH + 25.);
+        place(StaticObject { position, kind: StaticObjectKind::Tire, variant: 0 }, &mut static_objects);
+    }
+    // Drawn across the road where the finish line is
+    static_objects.push(StaticObject {
+        position: line[0],
+        kind: StaticObjectKind::FinishLine,
+        variant: 0,
+    });
+
+    let config = MapConfig {
+        name: format!("Generated {}", seed),
+        author: "generator".into(),
+        laps: 3,
+        par_time: None,
+        record_time: None,
+        time_limit: None,
+        allowed_powerups: None,
+        start_grid,
+        finish_line: gate(&line, 0),
+        powerups,
+        checkpoints,
+        static_objects,
+        terrain,
+    };
+    Ok(GeneratedMap { config, pixels })
+}
+
+fn write_png(path: &Path, color_type: png::ColorType, data: &[u8]) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("Could not write {}: {}", path.display(), e);
+    let file = File::create(path).map_err(|e| error(&e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), SIZE, SIZE);
+    encoder.set_color(color_type);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(data))
+        .map_err(|e| error(&e))
+}
+
+/// Writes the map generated from `seed` to its map folder, unless it
+/// already exists. Returns true if the map was generated
+pub fn ensure_generated(seed: u64) -> Result<bool, String> {
+    let name = map_name(seed);
+    let config_path = track::map_file(&name, "map.ron")?;
+    if config_path.exists() {
+        return Ok(false);
+    }
+    let terrain = MapConfig::load(constants::PALETTE_MAP)?.terrain;
+    let map = generate(seed, terrain)?;
+
+    let folder = config_path.parent().expect("Map files are in a folder");
+    fs::create_dir_all(folder)
+        .map_err(|e| format!("Could not create {}: {}", folder.display(), e))?;
+    write_png(&track::map_file(&name, "track.png")?, png::ColorType::RGB, &map.pixels)?;
+    // Generated maps have nothing drawn on top of the ground
+    let transparent = vec!(0; (SIZE * SIZE * 4) as usize);
+    write_png(&track::map_file(&name, "track_overlay.png")?, png::ColorType::RGBA, &transparent)?;
+    write_png(&track::map_file(&name, "track_overlay_overhead.png")?, png::ColorType::RGBA, &transparent)?;
+    // Written last, so that a map with a config is always complete
+    map.config.save(&name)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ground::Ground;
+    use crate::validation;
+
+    fn default_terrain() -> Vec<Terrain> {
+        let config: MapConfig = ron::de::from_str(
+            &std::fs::read_to_string("../resources/maps/default/map.ron").unwrap()
+        ).unwrap();
+        config.terrain
+    }
+
+    #[test]
+    fn seeds_give_the_same_map() {
+        let first = generate(7, default_terrain()).unwrap();
+        let second = generate(7, default_terrain()).unwrap();
+        assert!(first.pixels == second.pixels);
+        assert_eq!(
+            ron::ser::to_string(&first.config).unwrap(),
+            ron::ser::to_string(&second.config).unwrap()
+        );
+        assert!(generate(8, default_terrain()).unwrap().pixels != first.pixels);
+    }
+
+    #[test]
+    fn generated_maps_are_valid() {
+        for seed in 0..3 {
+            let map = generate(seed, default_terrain()).unwrap();
+            let ground = Ground::new(SIZE, SIZE, &map.pixels, map.config.terrain.clone()).unwrap();
+            let problems = validation::validate_map(&map.config, &ground);
+            assert!(problems.is_empty(), "seed {}: {:?}", seed, problems);
+        }
+    }
+
+    #[test]
+    fn map_names_contain_the_seed() {
+        assert_eq!(seed_from_map_name(&map_name(1234)), Some(1234));
+        assert_eq!(seed_from_map_name("default"), None);
+    }
+}
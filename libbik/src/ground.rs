@@ -1,18 +1,32 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+use serde_derive::{Serialize, Deserialize};
+#[cfg(feature = "sdl")]
 use sdl2::surface::Surface;
+#[cfg(feature = "sdl")]
 use sdl2::pixels::PixelFormatEnum;
 
 use crate::math::Vec2;
-use crate::constants::MAP_SCALE;
+use crate::constants::{MAP_SCALE, MAX_TERRAIN_COLOR_DISTANCE};
+
+pub type Color = (u8, u8, u8);
 
 #[derive(Debug, PartialEq)]
 pub enum GroundError {
-    UnknownKind(Vec<u8>),
-    Not3Pixels,
-    UnlockingFailed,
-    UnknownPixelFormat(PixelFormatEnum),
+    /// A pixel which is not close to any color in the palette
+    UnknownColor { color: Color, x: u32, y: u32 },
+    EmptyPalette,
+    TooManyTerrains(usize),
+    DuplicateColor(Color),
+    WrongSize { expected: usize, actual: usize },
+    UnsupportedColorType(String),
+    Decoding(String),
 }
 
-#[derive(Debug, PartialEq)]
+/// Terrain kinds with special behaviour besides their physics
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum TerrainType {
     Road,
     Puddle,
@@ -21,89 +35,261 @@ pub enum TerrainType {
     PitStop,
 }
 
-
-impl TerrainType {
-    pub fn braking_factor(&self) -> f32 {
-        match self {
-            TerrainType::Road | TerrainType::PitStop => 0.01,
-            TerrainType::Puddle => 0.005,
-            TerrainType::Sand => 0.03,
-            TerrainType::Obstacle => 0.1,
-        }
-    }
-
+/// How the part of the ground with a certain color in the ground image behaves
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Terrain {
+    pub color: Color,
+    pub kind: TerrainType,
+    pub braking_factor: f32,
     /// Rate of decay of side component of speed.
     /// A value of 1 makes any velocity not in the forward direction is removed in 1 second.
     ///
     /// A value of 0 makes the bike behave like a hovercraft
-    pub fn side_speed_decay(&self) -> f32 {
-        match self {
-            TerrainType::Road | TerrainType::PitStop=> 10.,
-            TerrainType::Puddle => 1.0,
-            TerrainType::Sand => 5.,
-            TerrainType::Obstacle => 1000.
+    pub side_speed_decay: f32,
+}
+
+fn color_distance(a: Color, b: Color) -> u32 {
+    let diff = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    diff(a.0, b.0) + diff(a.1, b.1) + diff(a.2, b.2)
+}
+
+/// Finds the index of the terrain with the closest color, as long as it is
+/// close enough to be a compression artifact rather than a mistake
+fn nearest_terrain(palette: &[Terrain], color: Color) -> Option<usize> {
+    palette.iter()
+        .enumerate()
+        .map(|(i, terrain)| (i, color_distance(terrain.color, color)))
+        .min_by_key(|(_, distance)| *distance)
+        .filter(|(_, distance)| *distance <= MAX_TERRAIN_COLOR_DISTANCE.pow(2))
+        .map(|(i, _)| i)
+}
+
+/// Checks that the palette can be used to build a ground
+pub fn validate_palette(palette: &[Terrain]) -> Result<(), GroundError> {
+    if palette.is_empty() {
+        return Err(GroundError::EmptyPalette);
+    }
+    if palette.len() > u8::MAX as usize + 1 {
+        return Err(GroundError::TooManyTerrains(palette.len()));
+    }
+    for (i, terrain) in palette.iter().enumerate() {
+        if palette[..i].iter().any(|other| other.color == terrain.color) {
+            return Err(GroundError::DuplicateColor(terrain.color));
         }
     }
+    Ok(())
 }
 
+/// Decodes a PNG file into its width, height and RGB pixels
+pub fn load_png(path: impl AsRef<Path>) -> Result<(u32, u32, Vec<u8>), GroundError> {
+    let file = File::open(path).map_err(|e| GroundError::Decoding(e.to_string()))?;
+    let mut decoder = png::Decoder::new(file);
+    // Palettes and small bit depths are expanded to 8 bit RGB
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()
+        .map_err(|e| GroundError::Decoding(e.to_string()))?;
+
+    let mut buffer = vec![0; info.buffer_size()];
+    reader.next_frame(&mut buffer)
+        .map_err(|e| GroundError::Decoding(e.to_string()))?;
+
+    let pixels = match info.color_type {
+        png::ColorType::RGB => buffer,
+        png::ColorType::RGBA => buffer.chunks(4)
+            .flat_map(|pixel| pixel[0..3].to_vec())
+            .collect(),
+        png::ColorType::Grayscale => buffer.iter()
+            .flat_map(|&value| vec!(value; 3))
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buffer.chunks(2)
+            .flat_map(|pixel| vec!(pixel[0]; 3))
+            .collect(),
+        other => return Err(GroundError::UnsupportedColorType(format!("{:?}", other))),
+    };
+    Ok((info.width, info.height, pixels))
+}
+
+/// Returns every color in the RGB pixels which does not match a terrain in
+/// the palette, along with the first pixel where it is used
+pub fn unknown_colors(width: u32, pixels: &[u8], palette: &[Terrain]) -> Vec<(Color, u32, u32)> {
+    let mut seen = HashSet::new();
+    let mut unknown = vec!();
+    for (i, pixel) in pixels.chunks(3).enumerate() {
+        let color = (pixel[0], pixel[1], pixel[2]);
+        if seen.insert(color) && nearest_terrain(palette, color).is_none() {
+            unknown.push((color, i as u32 % width, i as u32 / width));
+        }
+    }
+    unknown
+}
 
-pub struct Ground<'a> {
-    map: Surface<'a>,
-    stride: u32,
-    backward_pixels: bool
+/// The terrain of the map, one pixel per `MAP_SCALE` world units
+pub struct Ground {
+    width: u32,
+    height: u32,
+    palette: Vec<Terrain>,
+    /// Index into the palette for each pixel, row by row
+    terrain: Vec<u8>,
+    /// Index of the terrain used outside the image
+    outside: usize,
 }
 
-impl<'a> Ground<'a> {
-    pub fn new(map: Surface<'a>) -> Result<Self, GroundError> {
-        let (stride, backward_pixels) = match map.pixel_format_enum() {
-            PixelFormatEnum::ABGR8888 => Ok((4, true)),
-            PixelFormatEnum::RGB24 => Ok((3, false)),
-            other => Err(GroundError::UnknownPixelFormat(other))
-        }?;
-        Ok(Self {map, stride, backward_pixels})
+impl Ground {
+    /// Builds the ground from RGB pixels, failing if any of them does not
+    /// match a terrain in the palette
+    pub fn new(width: u32, height: u32, pixels: &[u8], palette: Vec<Terrain>) -> Result<Self, GroundError> {
+        validate_palette(&palette)?;
+        let expected = width as usize * height as usize * 3;
+        if pixels.len() != expected {
+            return Err(GroundError::WrongSize { expected, actual: pixels.len() });
+        }
+
+        // Images tend to have few distinct colors, so the lookups are cached
+        let mut known = HashMap::new();
+        let mut terrain = Vec::with_capacity(pixels.len() / 3);
+        for (i, pixel) in pixels.chunks(3).enumerate() {
+            let color = (pixel[0], pixel[1], pixel[2]);
+            let index = match known.get(&color) {
+                Some(index) => *index,
+                None => {
+                    let index = nearest_terrain(&palette, color).ok_or(GroundError::UnknownColor {
+                        color,
+                        x: i as u32 % width,
+                        y: i as u32 / width,
+                    })?;
+                    known.insert(color, index);
+                    index
+                }
+            };
+            terrain.push(index as u8);
+        }
+
+        let outside = palette.iter()
+            .position(|terrain| terrain.kind == TerrainType::Sand)
+            .unwrap_or(0);
+        Ok(Self {width, height, palette, terrain, outside})
+    }
+
+    pub fn from_png(path: impl AsRef<Path>, palette: Vec<Terrain>) -> Result<Self, GroundError> {
+        let (width, height, pixels) = load_png(path)?;
+        Self::new(width, height, &pixels, palette)
+    }
+
+    #[cfg(feature = "sdl")]
+    pub fn from_surface(surface: &Surface, palette: Vec<Terrain>) -> Result<Self, GroundError> {
+        // Bytes per pixel and whether they are stored in reverse order
+        let (stride, backward_pixels) = match surface.pixel_format_enum() {
+            PixelFormatEnum::ABGR8888 => (4, true),
+            PixelFormatEnum::RGB24 => (3, false),
+            other => return Err(GroundError::UnsupportedColorType(format!("{:?}", other))),
+        };
+        let (width, height) = surface.size();
+        let pitch = surface.pitch() as usize;
+
+        let pixels = surface.with_lock(|data| {
+            let mut pixels = Vec::with_capacity(width as usize * height as usize * 3);
+            for row in data.chunks(pitch).take(height as usize) {
+                for pixel in row.chunks(stride).take(width as usize) {
+                    if backward_pixels {
+                        pixels.extend(pixel.iter().rev().take(3));
+                    }
+                    else {
+                        pixels.extend_from_slice(&pixel[0..3]);
+                    }
+                }
+            }
+            pixels
+        });
+        Self::new(width, height, &pixels, palette)
+    }
+
+    /// Width and height in pixels
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
     }
 
-    pub fn query_terrain(&self, point: Vec2) -> Result<TerrainType, GroundError> {
+    /// Returns the terrain of a pixel inside the image
+    pub fn pixel_terrain(&self, x: u32, y: u32) -> &Terrain {
+        let index = self.terrain[(y * self.width + x) as usize];
+        &self.palette[index as usize]
+    }
+
+    /// Returns the terrain at a point in world coordinates. Points outside
+    /// the map are sand, or the first terrain if the palette has no sand
+    pub fn query_terrain(&self, point: Vec2) -> &Terrain {
         if point.x < 0. || point.y < 0. {
-            return Ok(TerrainType::Sand)
+            return &self.palette[self.outside];
         }
         let x = (point.x / MAP_SCALE) as u32;
         let y = (point.y / MAP_SCALE) as u32;
-        let w = self.map.width();
-        let h = self.map.height();
 
-        if x >= w || y >= h {
-            Ok(TerrainType::Sand)
+        if x >= self.width || y >= self.height {
+            &self.palette[self.outside]
         }
         else {
-            let array_index = ((y * self.map.width() + x) * self.stride) as usize;
+            self.pixel_terrain(x, y)
+        }
+    }
+}
 
-            let pixel = if let Some(data) = self.map.without_lock() {
-                &data[array_index..(array_index+4)]
-            }
-            else {
-                return Err(GroundError::UnlockingFailed)
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::vec2;
 
-            let reversed = if self.backward_pixels {
-                let result = pixel.iter().cloned().rev().collect::<Vec<u8>>();
-                result
-            }
-            else {
-                pixel.into()
-            };
+    fn terrain(color: Color, kind: TerrainType) -> Terrain {
+        Terrain {color, kind, braking_factor: 0.01, side_speed_decay: 10.}
+    }
 
-            let to_lookup = &reversed.as_slice()[0..3];
-            match to_lookup {
-                [255, 0,   0  ] => Ok(TerrainType::Obstacle),
-                [89,  126, 206] => Ok(TerrainType::Puddle),
-                [101, 81,  9  ] => Ok(TerrainType::Road),
-                [255, 204, 104] => Ok(TerrainType::Sand),
-                [199, 191, 43] => Ok(TerrainType::PitStop), // Yellow
-                [42, 40, 2] => Ok(TerrainType::PitStop),
-                x @ [_,   _, _] => Err(GroundError::UnknownKind(x.into())),
-                _ => Err(GroundError::Not3Pixels),
-            }
-        }
+    fn palette() -> Vec<Terrain> {
+        vec!(
+            terrain((101, 81, 9), TerrainType::Road),
+            terrain((89, 126, 206), TerrainType::Puddle),
+            terrain((255, 0, 0), TerrainType::Obstacle),
+            terrain((255, 204, 104), TerrainType::Sand),
+        )
+    }
+
+    #[test]
+    fn terrain_is_read_from_rgb_pixels() {
+        let pixels = [
+            101, 81, 9,     89, 126, 206,
+            255, 0, 0,      250, 200, 100,
+        ];
+        let ground = Ground::new(2, 2, &pixels, palette()).unwrap();
+        let at = |x: f32, y: f32| ground.query_terrain(vec2(x, y) * MAP_SCALE).kind;
+
+        assert_eq!(at(0., 0.), TerrainType::Road);
+        assert_eq!(at(1., 0.), TerrainType::Puddle);
+        assert_eq!(at(0., 1.), TerrainType::Obstacle);
+        // Slightly off colors use the nearest terrain
+        assert_eq!(at(1., 1.), TerrainType::Sand);
+        assert_eq!(at(5., 0.), TerrainType::Sand);
+    }
+
+    #[test]
+    fn unknown_colors_are_rejected() {
+        let pixels = [101, 81, 9, 0, 255, 0];
+        assert_eq!(
+            Ground::new(2, 1, &pixels, palette()).err(),
+            Some(GroundError::UnknownColor { color: (0, 255, 0), x: 1, y: 0 })
+        );
+    }
+
+    #[test]
+    fn buffers_of_the_wrong_size_are_rejected() {
+        assert_eq!(
+            Ground::new(2, 2, &[0; 11], palette()).err(),
+            Some(GroundError::WrongSize { expected: 12, actual: 11 })
+        );
+    }
+
+    #[test]
+    fn track_matches_the_map_palette() {
+        let map: crate::track::MapConfig = ron::de::from_str(
+            &std::fs::read_to_string("../resources/maps/default/map.ron").unwrap()
+        ).unwrap();
+        let ground = Ground::from_png("../resources/maps/default/track.png", map.terrain).unwrap();
+        assert_eq!((ground.width, ground.height), (2048, 2048));
     }
 }
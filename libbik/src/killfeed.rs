@@ -5,53 +5,62 @@ use crate::constants;
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct Message {
     pub message: String,
-    pub duration_left: Option<f32>,
+    /// The game state tick at which the message was added
+    pub tick: u64,
 }
 
 impl Message {
-    pub fn new(message: String) -> Self {
+    pub fn new(message: String, tick: u64) -> Self {
         Message {
             message,
-            duration_left: Some(constants::KILLFEED_DURATION),
+            tick,
         }
     }
+
+    /// Seconds since the message was added
+    pub fn age(&self, tick: u64) -> f32 {
+        tick.saturating_sub(self.tick) as f32 * constants::DELTA_TIME
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default)]
 pub struct KillFeed {
     pub messages: Vec<Message>,
+    /// Messages added since the last `take_new_messages`. The server sends
+    /// them to the clients once rather than as part of every snapshot
+    pub new_messages: Vec<Message>,
 }
 
 impl KillFeed {
     pub fn new() -> KillFeed {
         KillFeed {
             messages: vec!(),
+            new_messages: vec!(),
         }
     }
 
-    pub fn manage_killfeed(&mut self, delta: f32) {
-        // Decrease the messages time left
-        self.messages.iter_mut()
-            .for_each(|m| {
-                // Decrease the time left
-                m.duration_left = m.duration_left
-                    .map(|left| left - delta)
-            });
-
-        // Remove if the time is up
+    /// Removes the messages which have been shown for KILLFEED_DURATION
+    pub fn manage_killfeed(&mut self, tick: u64) {
         self.messages
-            .retain(|m| {
-                m.duration_left
-                    .map(|left| left > 0.)
-                    .unwrap_or(true)
-            });
+            .retain(|m| m.age(tick) < constants::KILLFEED_DURATION);
+    }
+
+    pub fn add_message(&mut self, message: &str, tick: u64) {
+        let message = Message::new(message.to_string(), tick);
+        self.new_messages.push(message.clone());
+        self.messages.push(message);
+    }
+
+    /// Adds a message which was added to the killfeed of the server
+    pub fn receive_message(&mut self, message: Message) {
+        self.messages.push(message);
     }
 
-    pub fn add_message(&mut self, message: &str) {
-        self.messages.push(Message::new(message.to_string()));
+    pub fn take_new_messages(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.new_messages)
     }
 
-    pub fn get_messages(&mut self) -> Vec<Message> {
+    pub fn get_messages(&self) -> Vec<Message> {
         if self.messages.len() >= 5 {
             self.messages[..4].to_vec()
         } else {
@@ -12,3 +12,9 @@ pub mod checkpoint;
 pub mod ground;
 pub mod static_object;
 pub mod weapon;
+pub mod snapshot;
+pub mod transport;
+pub mod network;
+pub mod validation;
+pub mod generator;
+pub mod killfeed;
@@ -145,6 +145,18 @@ impl LineSegment {
         }
     }
 
+    /// Positive for points to the right of the line as seen on screen when
+    /// looking from `p1` towards `p2`, negative for points to the left
+    pub fn side(&self, point: Vec2) -> f32 {
+        let direction = self.p2 - self.p1;
+        let offset = point - self.p1;
+        direction.x * offset.y - direction.y * offset.x
+    }
+
+    pub fn midpoint(&self) -> Vec2 {
+        (self.p1 + self.p2) / 2.
+    }
+
     pub fn intersects(&self, v: LineSegment) -> bool {
         let a1 = self.p2.y - self.p1.y;
         let b1 = self.p1.x - self.p2.x;
@@ -5,72 +5,141 @@ use std::iter::Iterator;
 
 use serde_derive::{Serialize, Deserialize};
 
+use crate::constants::MAX_FRAME_SIZE;
 use crate::player;
 use crate::math::Vec2;
+use crate::transport::Delivery;
 
-pub struct MessageReader {
-    pub stream: TcpStream,
+/// Version of the framing format, sent as the first byte of every frame so
+/// that incompatible peers are detected instead of misreading the stream
+pub const FRAME_VERSION: u8 = 1;
+/// Version of the messages sent inside frames. Must be increased whenever
+/// `ServerMessage` or `ClientMessage` change in an incompatible way
+pub const PROTOCOL_VERSION: u32 = 16;
+/// One version byte followed by a big endian u32 length
+pub const FRAME_HEADER_SIZE: usize = 5;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FrameError {
+    UnknownVersion(u8),
+    TooLarge(usize),
+}
+
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", error))
+    }
+}
+
+/// Prefixes `data` with a frame header
+pub fn encode_frame(data: &[u8]) -> Result<Vec<u8>, FrameError> {
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(data.len()));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+    frame.push(FRAME_VERSION);
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    Ok(frame)
+}
+
+pub struct MessageReader<S = TcpStream> {
+    pub stream: S,
     byte_queue: VecDeque<u8>,
+    /// Set once an invalid frame has been seen. Nothing after it can be
+    /// trusted so no more messages are returned
+    error: Option<FrameError>,
 }
 
-pub struct MessageIterator<'a> {
-    message_reader: &'a mut MessageReader
+pub struct MessageIterator<'a, S> {
+    message_reader: &'a mut MessageReader<S>
 }
 
-impl MessageReader {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: Read> MessageReader<S> {
+    pub fn new(stream: S) -> Self {
         Self {
             stream,
             byte_queue: VecDeque::new(),
+            error: None,
         }
     }
 
+    /// Reads everything available without blocking. Fails with
+    /// `UnexpectedEof` once the peer has closed the stream, but only after
+    /// the bytes which arrived before that have been fetched
     pub fn fetch_bytes(&mut self) -> io::Result<()> {
-        let mut buffer = [1; 64];
+        let mut buffer = [1; 4096];
+        let mut received_any = false;
         loop {
             let amount = match self.stream.read(&mut buffer) {
+                Ok(0) if received_any => break Ok(()),
+                Ok(0) => break Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed by peer",
+                )),
                 Ok(amount) => amount,
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => 0,
-                e => e?,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e),
             };
-            if amount == 0 {
-                break Ok(());
+            received_any = true;
+            if self.error.is_none() {
+                self.byte_queue.extend(buffer.iter().take(amount));
             }
-            self.byte_queue.extend(buffer.iter().take(amount));
         }
     }
 
-    pub fn iter(&mut self) -> MessageIterator<'_> {
+    pub fn iter(&mut self) -> MessageIterator<'_, S> {
         MessageIterator {
             message_reader: self
         }
     }
+
+    pub fn error(&self) -> Option<&FrameError> {
+        self.error.as_ref()
+    }
 }
 
-impl Iterator for MessageIterator<'_> {
-    type Item = Vec<u8>;
+impl<S> Iterator for MessageIterator<'_, S> {
+    type Item = Result<Vec<u8>, FrameError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // We need two bytes for the length
-        if self.message_reader.byte_queue.len() < 2 {
+        let reader = &mut self.message_reader;
+        if reader.error.is_some() || reader.byte_queue.len() < FRAME_HEADER_SIZE {
             return None;
         }
 
-        let length = u16::from_be_bytes([
-            self.message_reader.byte_queue[0],
-            self.message_reader.byte_queue[1],
+        let version = reader.byte_queue[0];
+        let length = u32::from_be_bytes([
+            reader.byte_queue[1],
+            reader.byte_queue[2],
+            reader.byte_queue[3],
+            reader.byte_queue[4],
         ]) as usize;
 
+        let error = if version != FRAME_VERSION {
+            Some(FrameError::UnknownVersion(version))
+        } else if length > MAX_FRAME_SIZE {
+            Some(FrameError::TooLarge(length))
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            reader.byte_queue.clear();
+            reader.error = Some(error.clone());
+            return Some(Err(error));
+        }
+
         // We will not read a message until a complete message has been
         // received
-        if self.message_reader.byte_queue.len() < 2 + length {
+        if reader.byte_queue.len() < FRAME_HEADER_SIZE + length {
             return None;
         }
 
-        self.message_reader.byte_queue.pop_front().unwrap();
-        self.message_reader.byte_queue.pop_front().unwrap();
+        reader.byte_queue.drain(0..FRAME_HEADER_SIZE);
 
-        Some(self.message_reader.byte_queue.drain(0..length).collect())
+        Some(Ok(reader.byte_queue.drain(0..length).collect()))
     }
 }
 
@@ -83,12 +152,49 @@ pub enum SoundEffect {
 
 #[derive(Serialize, Deserialize)]
 pub enum ServerMessage {
-    AssignId(u64),
-    GameState(crate::gamestate::GameState),
+    /// The client is not allowed to join, for example because it runs an
+    /// incompatible protocol version. Must stay the first variant so that
+    /// clients of any version can decode it
+    Rejected(String),
+    /// The token can be sent in `ClientMessage::Reconnect` to take the
+    /// player back if the connection is lost. `map` is the name of the map
+    /// folder the client should load
+    AssignId { id: u64, session_token: u64, map: String },
+    /// Answer to `ClientMessage::Reconnect` with the id of the reclaimed
+    /// player, or `None` if the session has expired
+    Reconnected(Option<u64>),
+    /// Map data which does not change during a race, sent when joining
+    StaticState(crate::snapshot::StaticState),
+    /// The changes to the game state along with the sequence number of the
+    /// last input from the receiving client that has been applied to it.
+    /// `tick` is the number of `DELTA_TIME` steps the server has simulated
+    GameState {
+        snapshot: crate::snapshot::SnapshotDelta,
+        last_input: u64,
+        tick: u64,
+    },
     PlaySound(SoundEffect, Vec2),
+    /// Something that happened during the race, such as a crash
+    GameEvent(crate::gamestate::GameEvent),
+    /// A new killfeed message, which the client removes once it is
+    /// KILLFEED_DURATION old
+    KillFeed(crate::killfeed::Message),
+    /// Sent periodically, the client answers with `Pong` so that idle
+    /// clients are not mistaken for dead ones
+    Ping,
+}
+
+impl ServerMessage {
+    pub fn delivery(&self) -> Delivery {
+        match self {
+            // Superseded by the next game state, no point in resending
+            ServerMessage::GameState { .. } | ServerMessage::Ping => Delivery::Unreliable,
+            _ => Delivery::Reliable,
+        }
+    }
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize, Clone)]
 pub struct ClientInput {
     pub x_input: f32,
     pub y_input: f32,
@@ -107,8 +213,122 @@ impl ClientInput {
 
 #[derive(Serialize, Deserialize)]
 pub enum ClientMessage {
-    Input(ClientInput),
+    /// The first message sent by a client, answered by `AssignId` or
+    /// `Rejected`. Must stay the first variant with the same fields so that
+    /// servers of any version can decode it
+    Hello { protocol_version: u32, client_build: String },
+    /// One simulation step worth of input. Sequence numbers are increasing
+    /// so that the server can tell the client which inputs it has processed
+    Input { sequence: u64, input: ClientInput },
     JoinGame { name: String },
     StartGame,
     RestartGame,
+    /// The id of the most recent snapshot the client has received
+    AckSnapshot(u64),
+    Pong,
+    /// The player quit, sent so that it is removed without waiting for a timeout
+    Leave,
+    /// Sent after the handshake to take over the player of a lost connection
+    Reconnect { session_token: u64 },
+}
+
+impl ClientMessage {
+    pub fn delivery(&self) -> Delivery {
+        match self {
+            ClientMessage::Input { .. }
+                | ClientMessage::AckSnapshot(_)
+                | ClientMessage::Pong => Delivery::Unreliable,
+            _ => Delivery::Reliable,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn reader_with(bytes: &[u8]) -> MessageReader<Cursor<Vec<u8>>> {
+        let mut reader = MessageReader::new(Cursor::new(bytes.to_vec()));
+        reader.fetch_bytes().unwrap();
+        reader
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let mut bytes = encode_frame(b"hello").unwrap();
+        bytes.extend(encode_frame(b"").unwrap());
+        bytes.extend(encode_frame(&[7; 70_000]).unwrap());
+
+        let mut reader = reader_with(&bytes);
+        let messages = reader.iter().collect::<Vec<_>>();
+
+        assert_eq!(messages, vec![
+            Ok(b"hello".to_vec()),
+            Ok(vec![]),
+            Ok(vec![7; 70_000]),
+        ]);
+    }
+
+    #[test]
+    fn split_frames_are_returned_once_complete() {
+        let frame = encode_frame(b"split message").unwrap();
+        let mut reader = reader_with(&frame[..3]);
+        assert_eq!(reader.iter().next(), None);
+
+        let position = reader.stream.position();
+        reader.stream.get_mut().extend_from_slice(&frame[3..10]);
+        reader.stream.set_position(position);
+        reader.fetch_bytes().unwrap();
+        assert_eq!(reader.iter().next(), None);
+
+        let position = reader.stream.position();
+        reader.stream.get_mut().extend_from_slice(&frame[10..]);
+        reader.stream.set_position(position);
+        reader.fetch_bytes().unwrap();
+        assert_eq!(reader.iter().next(), Some(Ok(b"split message".to_vec())));
+        assert_eq!(reader.iter().next(), None);
+    }
+
+    #[test]
+    fn closed_streams_fail_once_everything_is_fetched() {
+        let mut reader = reader_with(&encode_frame(b"last words").unwrap());
+        assert_eq!(reader.iter().next(), Some(Ok(b"last words".to_vec())));
+
+        let error = reader.fetch_bytes().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_frames_are_not_encoded() {
+        let data = vec![0; MAX_FRAME_SIZE + 1];
+        assert_eq!(encode_frame(&data), Err(FrameError::TooLarge(MAX_FRAME_SIZE + 1)));
+    }
+
+    #[test]
+    fn oversized_frames_stop_the_reader() {
+        let mut bytes = vec![FRAME_VERSION];
+        bytes.extend_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        bytes.extend(encode_frame(b"never read").unwrap());
+
+        let mut reader = reader_with(&bytes);
+        assert_eq!(
+            reader.iter().next(),
+            Some(Err(FrameError::TooLarge(MAX_FRAME_SIZE + 1)))
+        );
+        assert_eq!(reader.iter().next(), None);
+        assert!(reader.error().is_some());
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let mut bytes = encode_frame(b"from the future").unwrap();
+        bytes[0] = FRAME_VERSION + 1;
+
+        let mut reader = reader_with(&bytes);
+        assert_eq!(
+            reader.iter().next(),
+            Some(Err(FrameError::UnknownVersion(FRAME_VERSION + 1)))
+        );
+    }
 }
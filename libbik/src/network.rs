@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use polling::{Event, Poller};
+
+use crate::constants;
+use crate::transport::{Connection, Delivery, Listener};
+
+/// Identifies a connection owned by the network thread
+pub type ConnectionId = u64;
+
+pub enum NetworkEvent {
+    Connected(ConnectionId),
+    Message(ConnectionId, Vec<u8>),
+    /// The connection has been closed, no more events will arrive for it
+    Disconnected(ConnectionId, io::Error),
+}
+
+enum Command {
+    Send(ConnectionId, Delivery, Vec<u8>),
+    Close(ConnectionId),
+    CloseAfterFlush(ConnectionId),
+}
+
+/// Owns the listener and all connections on a separate thread, so that
+/// messages are read and written as soon as possible no matter what the
+/// simulation is doing
+pub struct NetworkThread {
+    events: Receiver<NetworkEvent>,
+    commands: Sender<Command>,
+    /// Woken up to let the thread know that there are new commands
+    poller: Arc<Poller>,
+}
+
+impl NetworkThread {
+    pub fn spawn(listener: Box<dyn Listener + Send>) -> Self {
+        let (event_sender, events) = mpsc::channel();
+        let (commands, command_receiver) = mpsc::channel();
+        let poller = Arc::new(Poller::new().expect("Could not create poller"));
+        let thread_poller = poller.clone();
+        thread::spawn(move || run(listener, thread_poller, event_sender, command_receiver));
+        Self {
+            events,
+            commands,
+            poller,
+        }
+    }
+
+    /// Queues a message, failures are reported as `NetworkEvent::Disconnected`
+    pub fn send(&self, connection: ConnectionId, delivery: Delivery, data: Vec<u8>) {
+        self.command(Command::Send(connection, delivery, data));
+    }
+
+    /// Drops the connection without sending a `NetworkEvent::Disconnected`
+    pub fn close(&self, connection: ConnectionId) {
+        self.command(Command::Close(connection));
+    }
+
+    /// Like `close`, but messages which have already been queued are sent
+    /// first, as long as that does not take longer than CLOSE_FLUSH_TIMEOUT
+    pub fn close_after_flush(&self, connection: ConnectionId) {
+        self.command(Command::CloseAfterFlush(connection));
+    }
+
+    fn command(&self, command: Command) {
+        self.commands.send(command).expect("Network thread stopped");
+        self.poller.notify().expect("Could not wake up the network thread");
+    }
+
+    /// Returns the next event, waiting for one until `deadline` has passed
+    pub fn next_event(&self, deadline: Instant) -> Option<NetworkEvent> {
+        match self.events.try_recv() {
+            Ok(event) => return Some(event),
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => panic!("Network thread stopped"),
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        match self.events.recv_timeout(deadline - now) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => panic!("Network thread stopped"),
+        }
+    }
+}
+
+// Connections use their id plus one as key
+const LISTENER_KEY: usize = 0;
+
+fn connection_key(id: ConnectionId) -> usize {
+    id as usize + 1
+}
+
+/// Stops waiting for the socket of a connection and drops it. Returns false
+/// if there was no such connection
+fn remove_connection(
+    poller: &Poller,
+    connections: &mut HashMap<ConnectionId, Box<dyn Connection>>,
+    id: ConnectionId,
+) -> bool {
+    match connections.remove(&id) {
+        Some(connection) => {
+            if let Some(source) = connection.raw_source() {
+                // The socket is closed right after, which unregisters it anyway
+                poller.delete(source).ok();
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+fn run(
+    mut listener: Box<dyn Listener + Send>,
+    poller: Arc<Poller>,
+    events: Sender<NetworkEvent>,
+    commands: Receiver<Command>,
+) {
+    let mut connections: HashMap<ConnectionId, Box<dyn Connection>> = HashMap::new();
+    // Closed connections which are still sending, and when to give up
+    let mut closing: HashMap<ConnectionId, Instant> = HashMap::new();
+    let mut next_id = 0;
+    let retry_interval = Duration::from_millis(constants::NETWORK_RETRY_INTERVAL);
+    let mut ready = vec!();
+
+    poller.add(listener.raw_source(), Event::readable(LISTENER_KEY))
+        .expect("Could not wait for the listener");
+
+    // Returning drops the connections, which happens when the receiving end
+    // of either channel is gone
+    macro_rules! emit {
+        ($event:expr) => {
+            if events.send($event).is_err() {
+                return;
+            }
+        };
+    }
+
+    loop {
+        loop {
+            match listener.accept() {
+                Ok(Some(connection)) => {
+                    if let Some(source) = connection.raw_source() {
+                        if let Err(e) = poller.add(source, Event::readable(connection_key(next_id))) {
+                            println!("Could not wait for new connection: {:?}", e);
+                            continue;
+                        }
+                    }
+                    connections.insert(next_id, connection);
+                    emit!(NetworkEvent::Connected(next_id));
+                    next_id += 1;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    println!("Socket listener error: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        let mut lost = vec!();
+        for (id, connection) in connections.iter_mut() {
+            if let Err(e) = connection.fetch_bytes() {
+                lost.push((*id, e));
+                continue;
+            }
+            while let Some(message) = connection.next_message() {
+                match message {
+                    // Nobody is listening to closed connections anymore
+                    Ok(_) if closing.contains_key(id) => {}
+                    Ok(message) => emit!(NetworkEvent::Message(*id, message)),
+                    Err(e) => {
+                        lost.push((*id, e.into()));
+                        break;
+                    }
+                }
+            }
+        }
+
+        loop {
+            match commands.try_recv() {
+                Ok(Command::Send(id, delivery, data)) => {
+                    if let Some(connection) = connections.get_mut(&id) {
+                        if let Err(e) = connection.send(delivery, &data) {
+                            lost.push((id, e));
+                        }
+                    }
+                }
+                Ok(Command::Close(id)) => {
+                    remove_connection(&poller, &mut connections, id);
+                    closing.remove(&id);
+                }
+                Ok(Command::CloseAfterFlush(id)) => {
+                    if connections.contains_key(&id) {
+                        let timeout = Duration::from_secs_f32(constants::CLOSE_FLUSH_TIMEOUT);
+                        closing.insert(id, Instant::now() + timeout);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        for (id, error) in lost {
+            let was_closing = closing.remove(&id).is_some();
+            if remove_connection(&poller, &mut connections, id) && !was_closing {
+                emit!(NetworkEvent::Disconnected(id, error));
+            }
+        }
+
+        let now = Instant::now();
+        closing.retain(|id, give_up_at| {
+            let done = connections.get(id)
+                .map(|connection| !connection.has_pending_writes() || now >= *give_up_at)
+                .unwrap_or(true);
+            if done {
+                remove_connection(&poller, &mut connections, *id);
+            }
+            !done
+        });
+
+        // Sleep until a socket becomes readable or a command arrives. Writes
+        // which did not go through and unacknowledged packets are retried
+        // by fetching again, which needs a timeout
+        let timeout = if connections.values().any(|c| c.has_pending_writes()) {
+            Some(retry_interval)
+        } else {
+            None
+        };
+        ready.clear();
+        if let Err(e) = poller.wait(&mut ready, timeout) {
+            println!("Could not wait for sockets: {:?}", e);
+            thread::sleep(retry_interval);
+        }
+
+        // Sources are only reported once until they are armed again
+        for event in &ready {
+            let source = if event.key == LISTENER_KEY {
+                Some(listener.raw_source())
+            } else {
+                connections.get(&(event.key as ConnectionId - 1))
+                    .and_then(|connection| connection.raw_source())
+            };
+            if let Some(source) = source {
+                poller.modify(source, Event::readable(event.key)).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use crate::transport::{TcpConnection, UdpConnection, UdpListener};
+
+    #[test]
+    fn ticks_stay_on_time_with_32_clients() {
+        const MESSAGES: u32 = 200;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let network = NetworkThread::spawn(Box::new(listener));
+
+        // Every client sends a numbered input each tick and checks the
+        // numbers of the game states it reads
+        let clients = thread::spawn(move || {
+            let mut clients = (0..32)
+                .map(|_| {
+                    let stream = TcpStream::connect(address).unwrap();
+                    stream.set_nonblocking(true).unwrap();
+                    TcpConnection::new(stream)
+                })
+                .collect::<Vec<_>>();
+            let mut last_received = vec!(None; clients.len());
+            for sequence in 0..MESSAGES {
+                for (client, last) in clients.iter_mut().zip(&mut last_received) {
+                    client.send(Delivery::Unreliable, &sequence.to_le_bytes()).unwrap();
+                    client.fetch_bytes().unwrap();
+                    while let Some(message) = client.next_message() {
+                        let message = message.unwrap();
+                        let received = u32::from_le_bytes([message[0], message[1], message[2], message[3]]);
+                        assert!(last.map(|last| received > last).unwrap_or(true));
+                        *last = Some(received);
+                    }
+                }
+                thread::sleep(Duration::from_secs_f32(constants::DELTA_TIME));
+            }
+            last_received
+        });
+
+        let tick = Duration::from_secs_f32(constants::DELTA_TIME);
+        let mut received = HashMap::new();
+        let mut lateness = vec!();
+        let mut next_tick = Instant::now() + tick;
+        let mut sequence = 0u32;
+        let start = Instant::now();
+        while received.len() < 32 || received.values().any(|count| *count < MESSAGES) {
+            assert!(start.elapsed() < Duration::from_secs(30), "Messages went missing");
+            while let Some(event) = network.next_event(next_tick) {
+                match event {
+                    NetworkEvent::Connected(id) => {
+                        received.insert(id, 0);
+                    }
+                    NetworkEvent::Message(id, message) => {
+                        let count = received.get_mut(&id).unwrap();
+                        assert_eq!(message, count.to_le_bytes());
+                        *count += 1;
+                    }
+                    NetworkEvent::Disconnected(_, e) => panic!("Client disconnected: {}", e),
+                }
+            }
+            lateness.push(Instant::now() - next_tick);
+            for id in received.keys() {
+                let mut state = sequence.to_le_bytes().to_vec();
+                state.resize(500, 0);
+                network.send(*id, Delivery::Unreliable, state);
+            }
+            sequence += 1;
+            next_tick += tick;
+        }
+
+        let last_received = clients.join().unwrap();
+        assert!(last_received.iter().all(Option::is_some));
+
+        // Loose enough for busy machines, but a network thread which holds
+        // up the simulation misses by whole ticks
+        lateness.sort();
+        let median = lateness[lateness.len() / 2];
+        let worst = lateness[lateness.len() * 99 / 100];
+        assert!(median < tick / 2, "median tick lateness {:?}", median);
+        assert!(worst < tick * 2, "99th percentile tick lateness {:?}", worst);
+    }
+
+    #[test]
+    fn queued_messages_are_sent_before_closing() {
+        let mut listener = UdpListener::bind("127.0.0.1:0").unwrap();
+        // Lost packets keep the message queued until it has been resent
+        listener.set_simulated_loss(0.3);
+        let address = listener.local_addr().unwrap();
+        let network = NetworkThread::spawn(Box::new(listener));
+
+        let mut client = UdpConnection::connect(address).unwrap();
+        let id = match network.next_event(Instant::now() + Duration::from_secs(5)) {
+            Some(NetworkEvent::Connected(id)) => id,
+            _ => panic!("Client did not connect"),
+        };
+        network.send(id, Delivery::Reliable, b"goodbye".to_vec());
+        network.close_after_flush(id);
+
+        let start = Instant::now();
+        let message = loop {
+            client.fetch_bytes().unwrap();
+            if let Some(message) = client.next_message() {
+                break message.unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "Message was never sent");
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(message, b"goodbye");
+    }
+
+    #[test]
+    fn closed_connections_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let address = listener.local_addr().unwrap();
+        let network = NetworkThread::spawn(Box::new(listener));
+
+        let client = TcpStream::connect(address).unwrap();
+        let id = match network.next_event(Instant::now() + Duration::from_secs(5)) {
+            Some(NetworkEvent::Connected(id)) => id,
+            _ => panic!("Client did not connect"),
+        };
+        drop(client);
+
+        match network.next_event(Instant::now() + Duration::from_secs(5)) {
+            Some(NetworkEvent::Disconnected(disconnected, e)) => {
+                assert_eq!(disconnected, id);
+                assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+            }
+            _ => panic!("Closed connection was not reported"),
+        }
+    }
+}
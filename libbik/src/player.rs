@@ -12,12 +12,14 @@ use crate::constants::{
     BIKE_SCALE,
     STEERING_ATTENUATION_MAX
 };
-use crate::math::{Vec2, vec2};
+use crate::checkpoint::{self, Checkpoint, Crossing};
+use crate::math::{LineSegment, Vec2, vec2};
 use crate::messages::ClientInput;
 use crate::constants;
 use crate::powerup::{self, Powerup, PowerupKind};
 use crate::ground::{TerrainType, Ground};
 use crate::gamestate::RaceState;
+use crate::static_object::StaticObjectKind;
 use crate::weapon::Weapon;
 use std::vec::Vec;
 use crate::messages::SoundEffect;
@@ -30,8 +32,19 @@ pub enum PlayerState {
     Falling(usize, f32),
     /// The player is fully crashed and has been so for x seconds
     Crashed(f32),
+    /// The player crashed without health left and has been lying on the
+    /// ground for x seconds
+    KnockedOut(f32),
 }
 
+/// What made a player crash. Players are referred to by id
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum CrashCause {
+    Mace { attacker: u64 },
+    /// `rammed` is set if the other player was the faster one
+    Collision { other: u64, rammed: bool },
+    StaticObject(StaticObjectKind),
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Player {
@@ -53,6 +66,12 @@ pub struct Player {
     pub checkpoint: usize,
 
     pub fuel_level: f32,
+    /// Decreased by crashes, the player is knocked out when it reaches 0
+    pub health: f32,
+    /// Set while health is being restored at a pit stop
+    pub repairing: bool,
+    /// Cause of the last crash
+    pub crash_cause: Option<CrashCause>,
 
     pub time_to_next_collision: f32,
 
@@ -62,6 +81,8 @@ pub struct Player {
     pub lap_times: Vec<f32>,
 
     pub finished: bool,
+    /// Set when the player drives backwards through a gate
+    pub wrong_way: bool,
 }
 
 
@@ -70,12 +91,13 @@ impl Player {
         id: u64,
         name: String,
         position: Vec2,
+        angle: f32,
     ) -> Player {
         Player {
             id,
             name,
             position,
-            angle: 0.,
+            angle,
             state: PlayerState::Upright,
             velocity: vec2(0., 0.),
             steering_angle: 0.,
@@ -84,12 +106,16 @@ impl Player {
             lap: 0,
             checkpoint: 0,
             fuel_level: constants::INITIAL_FUEL_LEVEL,
+            health: constants::MAX_HEALTH,
+            repairing: false,
+            crash_cause: None,
             time_to_next_collision: constants::COLLISION_GRACE_PERIOD,
             total_time: 0.,
             current_lap: 0.,
             best_lap: f32::INFINITY,
             lap_times: vec!(),
             finished: false,
+            wrong_way: false,
             carried_powerup: None,
         }
     }
@@ -108,7 +134,57 @@ impl Player {
         }
     }
 
-    pub fn add_lap(&mut self) {
+    pub fn update_health(&mut self, delta_time: f32, ground: &TerrainType) {
+        self.repairing = *ground == TerrainType::PitStop && self.health < constants::MAX_HEALTH;
+        if self.repairing {
+            self.health = (self.health + constants::REPAIR_SPEED * delta_time)
+                .min(constants::MAX_HEALTH)
+        }
+    }
+
+    /// Counts the gates passed when moving from `old_position` to the current
+    /// position. Laps are completed by crossing the finish line after all
+    /// checkpoints
+    pub fn update_progress(
+        &mut self,
+        old_position: Vec2,
+        checkpoints: &[Checkpoint],
+        finish_line: &LineSegment,
+        total_laps: usize
+    ) {
+        let next_gate = checkpoints.get(self.checkpoint)
+            .map(|checkpoint| &checkpoint.gate)
+            .unwrap_or(finish_line);
+        let previous_gate = match self.checkpoint {
+            0 => finish_line,
+            n => &checkpoints[n - 1].gate,
+        };
+
+        if checkpoint::gate_crossing(next_gate, old_position, self.position) == Some(Crossing::Forward) {
+            self.wrong_way = false;
+            if self.checkpoint < checkpoints.len() {
+                self.checkpoint += 1;
+            }
+            else {
+                self.add_lap(total_laps);
+                self.checkpoint = 0;
+            }
+            return;
+        }
+
+        match checkpoint::gate_crossing(previous_gate, old_position, self.position) {
+            Some(Crossing::Backward) => {
+                self.wrong_way = true;
+                // The gate has to be passed again before the next one counts
+                self.checkpoint = self.checkpoint.saturating_sub(1);
+            }
+            Some(Crossing::Forward) => self.wrong_way = false,
+            None => {}
+        }
+    }
+
+    /// Completes the current lap, finishing the race after `total_laps` laps
+    pub fn add_lap(&mut self, total_laps: usize) {
         self.lap_times.push(self.current_lap);
         if self.current_lap < self.best_lap {
             self.best_lap = self.current_lap;
@@ -116,7 +192,7 @@ impl Player {
         self.current_lap = 0.;
         self.lap += 1;
 
-        self.finished = self.lap >= constants::TOTAL_NUM_LAPS;
+        self.finished = self.lap >= total_laps;
 
         if self.finished {
             self.velocity = vec2(0., 0.);
@@ -167,7 +243,12 @@ impl Player {
                 if time > constants::FALLING_DURATION {
                     let new_stage = stage+1;
                     if new_stage >= constants::FALLING_STAGES {
-                        PlayerState::Crashed(0.)
+                        if self.health <= 0. {
+                            PlayerState::KnockedOut(0.)
+                        }
+                        else {
+                            PlayerState::Crashed(0.)
+                        }
                     }
                     else {
                         PlayerState::Falling(new_stage, time + delta_time)
@@ -185,6 +266,16 @@ impl Player {
                     PlayerState::Crashed(time + delta_time)
                 }
             }
+            PlayerState::KnockedOut(time) => {
+                if time > constants::KNOCKED_OUT_DURATION {
+                    self.health = constants::MAX_HEALTH;
+                    self.time_to_next_collision = constants::COLLISION_GRACE_PERIOD;
+                    PlayerState::Upright
+                }
+                else {
+                    PlayerState::KnockedOut(time + delta_time)
+                }
+            }
         };
     }
 
@@ -195,12 +286,11 @@ impl Player {
         delta_time: f32,
         race_state: &RaceState,
     ) {
-        let ground_type = ground.query_terrain(self.position)
-            .expect(&format!("failed to query terrain for player {:?}", self.name));
+        let ground_type = ground.query_terrain(self.position);
 
         let forward_dir = Vec2::from_direction(self.angle, 1.);
         let forward_component = forward_dir.dot(self.velocity);
-        let forward_decel_amount = ground_type.braking_factor() * forward_component;
+        let forward_decel_amount = ground_type.braking_factor * forward_component;
         let forward_decel = -forward_dir * (forward_decel_amount * delta_time)
             .max(forward_decel_amount);
 
@@ -222,7 +312,9 @@ impl Player {
 
                 let (throttle, steer_command) = match self.state {
                     PlayerState::Upright => (input.y_input, input.x_input),
-                    PlayerState::Crashed(_) | PlayerState::Falling(_, _) => (0., 0.)
+                    PlayerState::Crashed(_)
+                        | PlayerState::Falling(_, _)
+                        | PlayerState::KnockedOut(_) => (0., 0.)
                 };
 
                 let acc_magnitude = ACCELERATION *
@@ -244,7 +336,7 @@ impl Player {
                 let side_decel = {
                     let side_vel_magnitude = side_direction.dot(self.velocity);
 
-                    let decel = ground_type.side_speed_decay() * side_vel_magnitude;
+                    let decel = ground_type.side_speed_decay * side_vel_magnitude;
 
                     -side_direction * (decel * delta_time).max(side_vel_magnitude)
                 };
@@ -257,7 +349,8 @@ impl Player {
 
                 self.position += self.velocity * delta_time;
 
-                self.update_fuel_level(delta_time, throttle, &ground_type);
+                self.update_fuel_level(delta_time, throttle, &ground_type.kind);
+                self.update_health(delta_time, &ground_type.kind);
 
                 // Handle steering
                 let delta_angle = fwd_vel_magnitude * self.steering_angle.tan() / (WHEEL_DISTANCE * BIKE_SCALE);
@@ -309,11 +402,20 @@ impl Player {
         }
     }
 
-    pub fn crash(&mut self, mut add_sound: impl FnMut((SoundEffect, Vec2))) -> bool {
+    /// Makes the player fall and lose `damage` health, unless they crashed
+    /// recently. Returns whether they crashed
+    pub fn crash(
+        &mut self,
+        damage: f32,
+        cause: CrashCause,
+        mut add_sound: impl FnMut((SoundEffect, Vec2))
+    ) -> bool {
         if self.time_to_next_collision > 0. {
             return false;
         }
 
+        self.crash_cause = Some(cause);
+        self.health = (self.health - damage).max(0.);
 
         self.time_to_next_collision = constants::COLLISION_GRACE_PERIOD;
         self.state = PlayerState::Falling(0, 0.);
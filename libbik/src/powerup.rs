@@ -12,6 +12,23 @@ pub enum PowerupKind {
     Nitro(f32),
 }
 
+/// The kind of a powerup without its parameters, used to decide which
+/// powerups a map allows
+#[derive(PartialEq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum PowerupType {
+    Mace,
+    Nitro,
+}
+
+impl PowerupKind {
+    pub fn powerup_type(&self) -> PowerupType {
+        match self {
+            PowerupKind::Weapon(Weapon::Mace) => PowerupType::Mace,
+            PowerupKind::Nitro(_) => PowerupType::Nitro,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Powerup {
     pub position: Vec2,
@@ -0,0 +1,261 @@
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use serde_derive::{Serialize, Deserialize};
+
+use crate::checkpoint::Checkpoint;
+use crate::constants;
+use crate::gamestate::{RaceState, Standing};
+use crate::math::{LineSegment, Vec2};
+use crate::player::Player;
+use crate::powerup::Powerup;
+use crate::static_object::StaticObject;
+
+/// The parts of the game state that only change when a new map is loaded.
+/// Sent once when a client joins instead of every tick
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StaticState {
+    /// Name of the map folder the client should load
+    pub map: String,
+    /// Increased by the server whenever the files of the map have changed
+    pub map_revision: u64,
+    pub laps: usize,
+    pub checkpoints: Vec<Checkpoint>,
+    pub finish_line: LineSegment,
+    pub static_objects: Vec<StaticObject>,
+}
+
+/// The parts of the game state that change during a race
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Snapshot {
+    pub players: Vec<Player>,
+    pub powerups: Vec<Powerup>,
+    pub race_state: RaceState,
+    pub finished_players: Vec<u64>,
+    pub standings: Vec<Standing>,
+}
+
+impl Snapshot {
+    pub fn empty() -> Self {
+        Self {
+            players: vec!(),
+            powerups: vec!(),
+            race_state: RaceState::NotStarted,
+            finished_players: vec!(),
+            standings: vec!(),
+        }
+    }
+}
+
+/// Defines a struct containing an `Option` for each listed field of `$source`
+/// which is `Some` only if that field changed.
+///
+/// The field list is checked against the source struct at compile time, so
+/// adding a field to `$source` without adding it here is an error
+macro_rules! field_delta {
+    ($name:ident, $source:ident, { $($field:ident: $type:ty),* $(,)? }) => {
+        #[derive(Serialize, Deserialize, Clone, Default)]
+        pub struct $name {
+            $(pub $field: Option<$type>,)*
+        }
+
+        impl $name {
+            pub fn diff(old: &$source, new: &$source) -> Self {
+                let $source { $($field: _),* } = new;
+                Self {
+                    $($field: if old.$field != new.$field {
+                        Some(new.$field.clone())
+                    } else {
+                        None
+                    },)*
+                }
+            }
+
+            pub fn apply(&self, target: &mut $source) {
+                $(if let Some(value) = &self.$field {
+                    target.$field = value.clone();
+                })*
+            }
+
+            pub fn is_empty(&self) -> bool {
+                true $(&& self.$field.is_none())*
+            }
+        }
+    };
+}
+
+field_delta!(PlayerDelta, Player, {
+    id: u64,
+    name: String,
+    state: crate::player::PlayerState,
+    position: Vec2,
+    angle: f32,
+    velocity: Vec2,
+    steering_angle: f32,
+    carried_powerup: Option<crate::powerup::PowerupKind>,
+    nitro: f32,
+    weapon: Option<crate::weapon::Weapon>,
+    lap: usize,
+    checkpoint: usize,
+    fuel_level: f32,
+    health: f32,
+    repairing: bool,
+    crash_cause: Option<crate::player::CrashCause>,
+    time_to_next_collision: f32,
+    total_time: f32,
+    current_lap: f32,
+    best_lap: f32,
+    lap_times: Vec<f32>,
+    finished: bool,
+    wrong_way: bool,
+});
+
+field_delta!(PowerupDelta, Powerup, {
+    position: Vec2,
+    kind: crate::powerup::PowerupKind,
+    timeout: f32,
+});
+
+/// The difference between a snapshot and an earlier one that the receiver
+/// has acknowledged. A `base` of `None` means the delta is relative to
+/// `Snapshot::empty`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SnapshotDelta {
+    pub id: u64,
+    pub base: Option<u64>,
+    pub race_state: Option<RaceState>,
+    pub finished_players: Option<Vec<u64>>,
+    pub standings: Option<Vec<Standing>>,
+    pub changed_players: Vec<(u64, PlayerDelta)>,
+    pub new_players: Vec<Player>,
+    pub removed_players: Vec<u64>,
+    /// Number of powerups from the base which are still present
+    pub kept_powerups: usize,
+    pub changed_powerups: Vec<(usize, PowerupDelta)>,
+    pub new_powerups: Vec<Powerup>,
+}
+
+impl SnapshotDelta {
+    pub fn new(id: u64, base: Option<(u64, &Snapshot)>, snapshot: &Snapshot) -> Self {
+        let empty = Snapshot::empty();
+        let (base_id, base) = match base {
+            Some((base_id, base)) => (Some(base_id), base),
+            None => (None, &empty),
+        };
+
+        let mut changed_players = vec!();
+        let mut new_players = vec!();
+        for player in &snapshot.players {
+            match base.players.iter().find(|p| p.id == player.id) {
+                Some(old) => {
+                    let delta = PlayerDelta::diff(old, player);
+                    if !delta.is_empty() {
+                        changed_players.push((player.id, delta));
+                    }
+                }
+                None => new_players.push(player.clone()),
+            }
+        }
+
+        let removed_players = base.players.iter()
+            .map(|p| p.id)
+            .filter(|id| !snapshot.players.iter().any(|p| p.id == *id))
+            .collect();
+
+        let kept_powerups = base.powerups.len().min(snapshot.powerups.len());
+        let changed_powerups = base.powerups.iter()
+            .zip(snapshot.powerups.iter())
+            .map(|(old, new)| PowerupDelta::diff(old, new))
+            .enumerate()
+            .filter(|(_, delta)| !delta.is_empty())
+            .collect();
+        let new_powerups = snapshot.powerups[kept_powerups..].to_vec();
+
+        Self {
+            id,
+            base: base_id,
+            race_state: if base.race_state != snapshot.race_state {
+                Some(snapshot.race_state.clone())
+            } else {
+                None
+            },
+            finished_players: if base.finished_players != snapshot.finished_players {
+                Some(snapshot.finished_players.clone())
+            } else {
+                None
+            },
+            standings: if base.standings != snapshot.standings {
+                Some(snapshot.standings.clone())
+            } else {
+                None
+            },
+            changed_players,
+            new_players,
+            removed_players,
+            kept_powerups,
+            changed_powerups,
+            new_powerups,
+        }
+    }
+
+    /// Reconstructs the full snapshot from the base this delta was created from
+    pub fn apply(&self, base: &Snapshot) -> Snapshot {
+        let mut result = base.clone();
+
+        if let Some(race_state) = &self.race_state {
+            result.race_state = race_state.clone();
+        }
+        if let Some(finished_players) = &self.finished_players {
+            result.finished_players = finished_players.clone();
+        }
+        if let Some(standings) = &self.standings {
+            result.standings = standings.clone();
+        }
+
+        result.players.retain(|p| !self.removed_players.contains(&p.id));
+        for (id, delta) in &self.changed_players {
+            if let Some(player) = result.players.iter_mut().find(|p| p.id == *id) {
+                delta.apply(player);
+            }
+        }
+        result.players.extend(self.new_players.iter().cloned());
+
+        result.powerups.truncate(self.kept_powerups);
+        for (i, delta) in &self.changed_powerups {
+            if let Some(powerup) = result.powerups.get_mut(*i) {
+                delta.apply(powerup);
+            }
+        }
+        result.powerups.extend(self.new_powerups.iter().cloned());
+
+        result
+    }
+}
+
+/// The most recent snapshots sent or received, used as bases for deltas.
+/// The server shares each snapshot between the histories of all clients
+#[derive(Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(u64, Rc<Snapshot>)>,
+}
+
+impl SnapshotHistory {
+    pub fn new() -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, id: u64, snapshot: Rc<Snapshot>) {
+        self.snapshots.push_back((id, snapshot));
+        while self.snapshots.len() > constants::SNAPSHOT_HISTORY_LENGTH {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Snapshot> {
+        self.snapshots.iter()
+            .find(|(snapshot_id, _)| *snapshot_id == id)
+            .map(|(_, snapshot)| &**snapshot)
+    }
+}
@@ -2,7 +2,7 @@ use serde_derive::{Deserialize, Serialize};
 
 use crate::math::Vec2;
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum StaticObjectKind {
     Tree,
     Tire,
@@ -10,6 +10,19 @@ pub enum StaticObjectKind {
     Barrel,
 }
 
+impl StaticObjectKind {
+    /// Number of images there are of this kind, `StaticObject::variant` has
+    /// to be below this
+    pub fn variant_count(&self) -> usize {
+        match self {
+            StaticObjectKind::Tree => 3,
+            StaticObjectKind::Tire => 1,
+            StaticObjectKind::FinishLine => 1,
+            StaticObjectKind::Barrel => 1,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct StaticObject {
     pub position: Vec2,
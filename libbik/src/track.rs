@@ -1,13 +1,157 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
 use serde_derive::{Deserialize, Serialize};
 
-use crate::math::Vec2;
-use crate::powerup::Powerup;
+use crate::constants;
+use crate::generator;
+use crate::ground::{Ground, GroundError, Terrain};
+use crate::math::{LineSegment, Vec2};
+use crate::powerup::{Powerup, PowerupKind, PowerupType};
 use crate::static_object::StaticObject;
 
+/// A place on the start grid
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StartSlot {
+    pub position: Vec2,
+    /// Direction the bike is facing in radians
+    pub angle: f32,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MapConfig {
-    pub start_position: Vec2,
+    /// Name shown to players, the folder name is used everywhere else
+    pub name: String,
+    #[serde(default)]
+    pub author: String,
+    /// Number of laps in a race
+    pub laps: usize,
+    /// Lap time in seconds that a decent player should beat
+    #[serde(default)]
+    pub par_time: Option<f32>,
+    /// Best lap time driven on the map
+    #[serde(default)]
+    pub record_time: Option<f32>,
+    /// Seconds after the start when the race is ended even if some players
+    /// have not finished
+    #[serde(default)]
+    pub time_limit: Option<f32>,
+    /// Powerups which may be placed on the map, all are allowed if missing
+    #[serde(default)]
+    pub allowed_powerups: Option<Vec<PowerupType>>,
+    /// Places of the players at the start, in order of joining
+    pub start_grid: Vec<StartSlot>,
+    /// Laps are completed by crossing this after the last checkpoint
+    pub finish_line: LineSegment,
     pub powerups: Vec<Powerup>,
-    pub checkpoints: Vec<Vec2>,
+    /// Gates which have to be crossed in order, see `checkpoint::gate_crossing`
+    pub checkpoints: Vec<LineSegment>,
     pub static_objects: Vec<StaticObject>,
+    /// Maps colors in the ground image to terrain
+    pub terrain: Vec<Terrain>,
+}
+
+impl MapConfig {
+    /// Loads `map.ron` from the folder of the map called `name`. Generated
+    /// maps are written first if they do not exist yet
+    pub fn load(name: &str) -> Result<Self, String> {
+        if let Some(seed) = generator::seed_from_map_name(name) {
+            generator::ensure_generated(seed)?;
+        }
+        let path = map_file(name, "map.ron")?;
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+        ron::de::from_str(&text)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))
+    }
+
+    /// Writes the config to `map.ron` in the folder of the map called `name`.
+    /// Comments in the old file are lost
+    pub fn save(&self, name: &str) -> Result<(), String> {
+        let path = map_file(name, "map.ron")?;
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .map_err(|e| format!("Could not encode {}: {}", path.display(), e))?;
+        fs::write(&path, text)
+            .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+    }
+
+    pub fn allows_powerup(&self, kind: &PowerupKind) -> bool {
+        match &self.allowed_powerups {
+            Some(allowed) => allowed.contains(&kind.powerup_type()),
+            None => true,
+        }
+    }
+
+    /// The powerups of the map which are allowed by its rules
+    pub fn allowed_powerups(&self) -> Vec<Powerup> {
+        self.powerups.iter()
+            .filter(|powerup| self.allows_powerup(&powerup.kind))
+            .cloned()
+            .collect()
+    }
+
+    /// Loads `track.png` from the folder of the map called `name`
+    pub fn load_ground(&self, name: &str) -> Result<Ground, String> {
+        let path = map_file(name, "track.png")?;
+        Ground::from_png(&path, self.terrain.clone())
+            .map_err(|e: GroundError| format!("Could not load {}: {:?}", path.display(), e))
+    }
+}
+
+/// Notices changes to the files of a map by polling their modification times
+pub struct MapWatcher {
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+impl MapWatcher {
+    pub fn new(name: &str) -> Result<Self, String> {
+        let files = MAP_FILES.iter()
+            .map(|file| {
+                let path = map_file(name, file)?;
+                let modified = modified_time(&path);
+                Ok((path, modified))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { files })
+    }
+
+    /// Returns true if any of the files changed since the last call
+    pub fn changed(&mut self) -> bool {
+        let mut changed = false;
+        for (path, modified) in &mut self.files {
+            let now = modified_time(path);
+            if now != *modified {
+                *modified = now;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// Files which make up a map
+const MAP_FILES: [&str; 4] = [
+    "map.ron",
+    "track.png",
+    "track_overlay.png",
+    "track_overlay_overhead.png",
+];
+
+/// Map names are sent over the network, so they are not allowed to point
+/// anywhere outside the maps directory
+pub fn is_valid_map_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// The path of `file` in the folder of the map called `name`
+pub fn map_file(name: &str, file: &str) -> Result<PathBuf, String> {
+    if !is_valid_map_name(name) {
+        return Err(format!("Invalid map name {:?}", name));
+    }
+    Ok(Path::new(constants::MAPS_DIRECTORY).join(name).join(file))
 }
@@ -0,0 +1,886 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+
+use crate::constants;
+use crate::messages::{self, FrameError, MessageReader, FRAME_VERSION};
+
+/// How a message should be delivered. Transports which are always reliable,
+/// like TCP, ignore this
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Delivery {
+    /// Delivered exactly once and in order, resent until acknowledged
+    Reliable,
+    /// May be lost, but is never delivered after a newer unreliable message
+    Unreliable,
+}
+
+/// A socket which can be waited on for incoming data
+#[cfg(unix)]
+pub type RawSource = RawFd;
+#[cfg(windows)]
+pub type RawSource = RawSocket;
+
+#[cfg(unix)]
+fn raw_source(socket: &impl AsRawFd) -> RawSource {
+    socket.as_raw_fd()
+}
+
+#[cfg(windows)]
+fn raw_source(socket: &impl AsRawSocket) -> RawSource {
+    socket.as_raw_socket()
+}
+
+/// A connection to a single peer which messages can be sent over
+pub trait Connection {
+    fn send(&mut self, delivery: Delivery, data: &[u8]) -> io::Result<()>;
+    /// Reads everything currently available from the network
+    fn fetch_bytes(&mut self) -> io::Result<()>;
+    /// Returns the next complete message which has been fetched
+    fn next_message(&mut self) -> Option<Result<Vec<u8>, FrameError>>;
+    /// True if sent messages are still waiting to be written or acknowledged.
+    /// They are sent by later calls to `fetch_bytes`
+    fn has_pending_writes(&self) -> bool;
+    /// The socket which becomes readable when there is something to fetch,
+    /// or `None` if the data arrives through the listener
+    fn raw_source(&self) -> Option<RawSource>;
+}
+
+pub struct ConnectionIterator<'a> {
+    connection: &'a mut dyn Connection,
+}
+
+impl Iterator for ConnectionIterator<'_> {
+    type Item = Result<Vec<u8>, FrameError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.connection.next_message()
+    }
+}
+
+impl<'c> dyn Connection + 'c {
+    pub fn iter(&mut self) -> ConnectionIterator<'_> {
+        ConnectionIterator {
+            connection: self
+        }
+    }
+}
+
+/// Accepts new connections without blocking
+pub trait Listener {
+    fn accept(&mut self) -> io::Result<Option<Box<dyn Connection>>>;
+    /// The socket which becomes readable when there is something to accept
+    fn raw_source(&self) -> RawSource;
+}
+
+/// A TCP connection which queues outgoing frames instead of blocking when
+/// the peer is slow to read them
+pub struct TcpConnection {
+    reader: MessageReader<TcpStream>,
+    /// Encoded frames which have not been completely written yet
+    outbound: VecDeque<(Delivery, Vec<u8>)>,
+    /// Number of bytes of the first outbound frame which have been written
+    written: usize,
+    queued_bytes: usize,
+}
+
+// Bincode encodes the variant of an enum as a u32
+const MESSAGE_KIND_SIZE: usize = 4;
+
+impl TcpConnection {
+    /// The stream has to be nonblocking
+    pub fn new(stream: TcpStream) -> Self {
+        Self {
+            reader: MessageReader::new(stream),
+            outbound: VecDeque::new(),
+            written: 0,
+            queued_bytes: 0,
+        }
+    }
+
+    /// Number of bytes waiting to be written
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Writes as much of the outbound queue as possible without blocking
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some((_, frame)) = self.outbound.front() {
+            match self.reader.stream.write(&frame[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    if self.written == frame.len() {
+                        self.queued_bytes -= frame.len();
+                        self.written = 0;
+                        self.outbound.pop_front();
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes unreliable frames which are superseded by a newer one of the
+    /// same kind. The kind is the first MESSAGE_KIND_SIZE bytes of the
+    /// message, which is the variant of the serialized message enum, so that
+    /// a ping does not replace a game state
+    fn drop_stale_frames(&mut self) {
+        // A partially written frame has to be finished or the stream breaks
+        let started = self.written > 0;
+        let mut seen_kinds = HashSet::new();
+        let mut kept = VecDeque::new();
+
+        let frames = std::mem::take(&mut self.outbound).into_iter().enumerate().rev();
+        for (i, (delivery, frame)) in frames {
+            let kind = frame.iter()
+                .skip(messages::FRAME_HEADER_SIZE)
+                .take(MESSAGE_KIND_SIZE)
+                .cloned()
+                .collect::<Vec<_>>();
+            let newest_of_kind = seen_kinds.insert(kind);
+            if delivery == Delivery::Reliable || newest_of_kind || started && i == 0 {
+                kept.push_front((delivery, frame));
+            }
+        }
+        self.outbound = kept;
+        self.queued_bytes = self.outbound.iter().map(|(_, frame)| frame.len()).sum();
+    }
+}
+
+impl Connection for TcpConnection {
+    fn send(&mut self, delivery: Delivery, data: &[u8]) -> io::Result<()> {
+        let frame = messages::encode_frame(data)?;
+        self.queued_bytes += frame.len();
+        self.outbound.push_back((delivery, frame));
+
+        if self.queued_bytes > constants::OUTBOUND_HIGH_WATER_MARK {
+            self.drop_stale_frames();
+        }
+        if self.queued_bytes > constants::OUTBOUND_LIMIT {
+            return Err(io::Error::other("Peer is not reading, outbound queue is full"));
+        }
+        self.flush()
+    }
+
+    fn fetch_bytes(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.reader.fetch_bytes()
+    }
+
+    fn next_message(&mut self) -> Option<Result<Vec<u8>, FrameError>> {
+        self.reader.iter().next()
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        !self.outbound.is_empty()
+    }
+
+    fn raw_source(&self) -> Option<RawSource> {
+        Some(raw_source(&self.reader.stream))
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> io::Result<Option<Box<dyn Connection>>> {
+        match TcpListener::accept(self) {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true)?;
+                Ok(Some(Box::new(TcpConnection::new(stream))))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    fn raw_source(&self) -> RawSource {
+        raw_source(self)
+    }
+}
+
+// The first byte of every datagram after the version
+const PACKET_UNRELIABLE: u8 = 0;
+const PACKET_RELIABLE: u8 = 1;
+const PACKET_ACK: u8 = 2;
+const PACKET_CONNECT: u8 = 3;
+// Part of an unreliable message too large for one datagram. The payload
+// starts with the u16 index of the fragment and the u16 number of fragments
+const PACKET_UNRELIABLE_FRAGMENT: u8 = 4;
+// A reliable packet whose message continues in the next reliable packet
+const PACKET_RELIABLE_FRAGMENT: u8 = 5;
+// The answer to PACKET_CONNECT, so that the client knows it was heard even if
+// the server has nothing to say yet
+const PACKET_CONNECT_ACK: u8 = 6;
+// Version, packet kind and a u32 sequence number
+const DATAGRAM_HEADER_SIZE: usize = 6;
+const FRAGMENT_HEADER_SIZE: usize = 4;
+const MAX_DATAGRAM_PAYLOAD: usize = constants::MAX_DATAGRAM_SIZE - DATAGRAM_HEADER_SIZE;
+
+fn datagram(kind: u8, sequence: u32, payload: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(DATAGRAM_HEADER_SIZE + payload.len());
+    result.push(FRAME_VERSION);
+    result.push(kind);
+    result.extend_from_slice(&sequence.to_be_bytes());
+    result.extend_from_slice(payload);
+    result
+}
+
+/// Splits a message into parts of at most `size` bytes. Empty messages are
+/// still sent as one part
+fn fragments(data: &[u8], size: usize) -> Vec<&[u8]> {
+    if data.is_empty() {
+        vec!(data)
+    }
+    else {
+        data.chunks(size).collect()
+    }
+}
+
+/// Where a `UdpConnection` gets its datagrams from
+enum Inbox {
+    /// The connection has a socket connected to its peer
+    Socket,
+    /// The socket is shared with other connections and a `UdpListener`
+    /// forwards the datagrams from our peer
+    Channel(Receiver<Vec<u8>>),
+}
+
+struct UnackedPacket {
+    sequence: u32,
+    datagram: Vec<u8>,
+    last_sent: Instant,
+}
+
+/// A connection over UDP with a small reliability layer on top for
+/// messages which have to arrive. Messages which do not fit in a datagram
+/// are split into fragments, unreliable ones are lost if any fragment is
+pub struct UdpConnection {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    inbox: Inbox,
+    messages: VecDeque<Result<Vec<u8>, FrameError>>,
+    /// Set once anything has been received from the peer
+    received_any: bool,
+
+    next_unreliable_send: u32,
+    last_unreliable_received: Option<u32>,
+    /// Sequence number and fragments received so far of the newest
+    /// fragmented unreliable message
+    partial_unreliable: Option<(u32, Vec<Option<Vec<u8>>>)>,
+
+    next_reliable_send: u32,
+    unacked: Vec<UnackedPacket>,
+    next_reliable_received: u32,
+    /// Reliable packets which arrived before some earlier packet, along with
+    /// their kind
+    out_of_order: BTreeMap<u32, (u8, Vec<u8>)>,
+    /// Fragments of the reliable message currently being received
+    partial_reliable: Vec<u8>,
+
+    /// Fraction of outgoing datagrams to drop, for testing bad networks
+    simulated_loss: f32,
+}
+
+impl UdpConnection {
+    fn new(socket: Arc<UdpSocket>, peer: SocketAddr, inbox: Inbox) -> Self {
+        Self {
+            socket,
+            peer,
+            inbox,
+            messages: VecDeque::new(),
+            received_any: false,
+            next_unreliable_send: 0,
+            last_unreliable_received: None,
+            partial_unreliable: None,
+            next_reliable_send: 0,
+            unacked: vec!(),
+            next_reliable_received: 0,
+            out_of_order: BTreeMap::new(),
+            partial_reliable: vec!(),
+            simulated_loss: 0.,
+        }
+    }
+
+    /// Creates a connection from a socket which is already connected to
+    /// its peer
+    pub fn from_socket(socket: UdpSocket) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let peer = socket.peer_addr()?;
+        Ok(Self::new(Arc::new(socket), peer, Inbox::Socket))
+    }
+
+    /// Connects to a server running a `UdpListener`, blocking until the
+    /// server has responded
+    pub fn connect(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let peer = address.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address"))?;
+        let local = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(peer)?;
+
+        let mut connection = Self::from_socket(socket)?;
+        let start = Instant::now();
+        while !connection.received_any {
+            if start.elapsed() > Duration::from_secs_f32(constants::UDP_CONNECT_TIMEOUT) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Server did not respond"));
+            }
+            connection.send_datagram(datagram(PACKET_CONNECT, 0, &[]))?;
+            std::thread::sleep(Duration::from_secs_f32(constants::UDP_RESEND_INTERVAL));
+            connection.fetch_bytes()?;
+        }
+        Ok(connection)
+    }
+
+    pub fn set_simulated_loss(&mut self, loss: f32) {
+        self.simulated_loss = loss;
+    }
+
+    fn send_datagram(&self, datagram: Vec<u8>) -> io::Result<()> {
+        if self.simulated_loss > 0. && rand::random::<f32>() < self.simulated_loss {
+            return Ok(());
+        }
+
+        let result = match self.inbox {
+            Inbox::Socket => self.socket.send(&datagram),
+            Inbox::Channel(_) => self.socket.send_to(&datagram, self.peer),
+        };
+        match result {
+            Ok(_) => Ok(()),
+            // The socket buffer is full, treat it like a lost packet
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn receive_datagrams(&mut self) -> io::Result<Vec<Vec<u8>>> {
+        let mut result = vec!();
+        match &self.inbox {
+            Inbox::Socket => {
+                let mut buffer = [0; constants::MAX_DATAGRAM_SIZE];
+                loop {
+                    match self.socket.recv(&mut buffer) {
+                        Ok(amount) => result.push(buffer[..amount].to_vec()),
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Inbox::Channel(receiver) => {
+                result.extend(receiver.try_iter());
+            }
+        }
+        Ok(result)
+    }
+
+    fn handle_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if datagram.len() < DATAGRAM_HEADER_SIZE {
+            return Ok(());
+        }
+        if datagram[0] != FRAME_VERSION {
+            self.messages.push_back(Err(FrameError::UnknownVersion(datagram[0])));
+            return Ok(());
+        }
+        self.received_any = true;
+
+        let kind = datagram[1];
+        let sequence = u32::from_be_bytes([datagram[2], datagram[3], datagram[4], datagram[5]]);
+        let payload = &datagram[DATAGRAM_HEADER_SIZE..];
+
+        match kind {
+            PACKET_UNRELIABLE if self.is_newest_unreliable(sequence) => {
+                self.deliver_unreliable(sequence, payload.to_vec());
+            }
+            PACKET_UNRELIABLE_FRAGMENT
+                if payload.len() >= FRAGMENT_HEADER_SIZE && self.is_newest_unreliable(sequence) =>
+            {
+                let index = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+                let count = u16::from_be_bytes([payload[2], payload[3]]) as usize;
+                self.receive_unreliable_fragment(sequence, index, count, &payload[FRAGMENT_HEADER_SIZE..]);
+            }
+            PACKET_RELIABLE | PACKET_RELIABLE_FRAGMENT => {
+                // Always acknowledge, our previous ack may have been lost
+                self.send_datagram(self::datagram(PACKET_ACK, sequence, &[]))?;
+
+                if sequence == self.next_reliable_received {
+                    self.deliver_reliable(kind, payload.to_vec());
+                    self.next_reliable_received += 1;
+                    while let Some((kind, next)) = self.out_of_order.remove(&self.next_reliable_received) {
+                        self.deliver_reliable(kind, next);
+                        self.next_reliable_received += 1;
+                    }
+                } else if sequence > self.next_reliable_received {
+                    self.out_of_order.insert(sequence, (kind, payload.to_vec()));
+                }
+            }
+            PACKET_ACK => {
+                self.unacked.retain(|packet| packet.sequence != sequence);
+            }
+            // Sent repeatedly while connecting, until one of the answers arrives
+            PACKET_CONNECT => {
+                self.send_datagram(self::datagram(PACKET_CONNECT_ACK, 0, &[]))?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn is_newest_unreliable(&self, sequence: u32) -> bool {
+        self.last_unreliable_received
+            .map(|last| sequence > last)
+            .unwrap_or(true)
+    }
+
+    fn deliver_unreliable(&mut self, sequence: u32, message: Vec<u8>) {
+        self.last_unreliable_received = Some(sequence);
+        // Older fragmented messages can never be delivered now
+        if let Some((partial, _)) = self.partial_unreliable {
+            if partial <= sequence {
+                self.partial_unreliable = None;
+            }
+        }
+        self.messages.push_back(Ok(message));
+    }
+
+    fn receive_unreliable_fragment(&mut self, sequence: u32, index: usize, count: usize, data: &[u8]) {
+        if index >= count {
+            return;
+        }
+        let starts_new = match &self.partial_unreliable {
+            Some((partial, fragments)) =>
+                sequence > *partial || sequence == *partial && fragments.len() != count,
+            None => true,
+        };
+        if starts_new {
+            self.partial_unreliable = Some((sequence, vec!(None; count)));
+        }
+
+        let complete = match &mut self.partial_unreliable {
+            // Fragments of a message older than the one being received
+            Some((partial, _)) if *partial != sequence => return,
+            Some((_, fragments)) => {
+                fragments[index] = Some(data.to_vec());
+                fragments.iter().all(Option::is_some)
+            }
+            None => return,
+        };
+        if complete {
+            let (_, fragments) = self.partial_unreliable.take().expect("Checked above");
+            self.deliver_unreliable(sequence, fragments.into_iter().flatten().flatten().collect());
+        }
+    }
+
+    fn deliver_reliable(&mut self, kind: u8, payload: Vec<u8>) {
+        self.partial_reliable.extend(payload);
+        if kind == PACKET_RELIABLE {
+            self.messages.push_back(Ok(std::mem::take(&mut self.partial_reliable)));
+        }
+    }
+
+    fn resend_unacked(&mut self) -> io::Result<()> {
+        let interval = Duration::from_secs_f32(constants::UDP_RESEND_INTERVAL);
+        for i in 0..self.unacked.len() {
+            if self.unacked[i].last_sent.elapsed() > interval {
+                self.send_datagram(self.unacked[i].datagram.clone())?;
+                self.unacked[i].last_sent = Instant::now();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Connection for UdpConnection {
+    fn send(&mut self, delivery: Delivery, data: &[u8]) -> io::Result<()> {
+        // The same limit as for TCP frames
+        if data.len() > constants::MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(data.len()).into());
+        }
+
+        match delivery {
+            Delivery::Unreliable => {
+                let sequence = self.next_unreliable_send;
+                self.next_unreliable_send += 1;
+                if data.len() <= MAX_DATAGRAM_PAYLOAD {
+                    return self.send_datagram(datagram(PACKET_UNRELIABLE, sequence, data));
+                }
+                let parts = fragments(data, MAX_DATAGRAM_PAYLOAD - FRAGMENT_HEADER_SIZE);
+                for (index, part) in parts.iter().enumerate() {
+                    let mut payload = Vec::with_capacity(FRAGMENT_HEADER_SIZE + part.len());
+                    payload.extend_from_slice(&(index as u16).to_be_bytes());
+                    payload.extend_from_slice(&(parts.len() as u16).to_be_bytes());
+                    payload.extend_from_slice(part);
+                    self.send_datagram(datagram(PACKET_UNRELIABLE_FRAGMENT, sequence, &payload))?;
+                }
+                Ok(())
+            }
+            Delivery::Reliable => {
+                let parts = fragments(data, MAX_DATAGRAM_PAYLOAD);
+                for (index, part) in parts.iter().enumerate() {
+                    let kind = if index + 1 < parts.len() { PACKET_RELIABLE_FRAGMENT } else { PACKET_RELIABLE };
+                    let sequence = self.next_reliable_send;
+                    self.next_reliable_send += 1;
+                    let datagram = datagram(kind, sequence, part);
+                    self.send_datagram(datagram.clone())?;
+                    self.unacked.push(UnackedPacket {
+                        sequence,
+                        datagram,
+                        last_sent: Instant::now(),
+                    });
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn fetch_bytes(&mut self) -> io::Result<()> {
+        for datagram in self.receive_datagrams()? {
+            self.handle_datagram(&datagram)?;
+        }
+        self.resend_unacked()
+    }
+
+    fn next_message(&mut self) -> Option<Result<Vec<u8>, FrameError>> {
+        self.messages.pop_front()
+    }
+
+    fn has_pending_writes(&self) -> bool {
+        !self.unacked.is_empty()
+    }
+
+    fn raw_source(&self) -> Option<RawSource> {
+        match self.inbox {
+            Inbox::Socket => Some(raw_source(&*self.socket)),
+            Inbox::Channel(_) => None,
+        }
+    }
+}
+
+/// Accepts UDP connections on a single socket shared by all clients
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    peers: HashMap<SocketAddr, Sender<Vec<u8>>>,
+    new_connections: VecDeque<UdpConnection>,
+    /// Applied to all accepted connections
+    simulated_loss: f32,
+}
+
+impl UdpListener {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            peers: HashMap::new(),
+            new_connections: VecDeque::new(),
+            simulated_loss: 0.,
+        })
+    }
+
+    pub fn set_simulated_loss(&mut self, loss: f32) {
+        self.simulated_loss = loss;
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Reads all pending datagrams and forwards them to their connections
+    fn dispatch(&mut self) -> io::Result<()> {
+        let mut buffer = [0; constants::MAX_DATAGRAM_SIZE];
+        loop {
+            let (amount, peer) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                // Some platforms report unreachable peers on the shared socket.
+                // Those peers will time out on their own
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => break Err(e),
+            };
+            let datagram = buffer[..amount].to_vec();
+
+            let delivered = match self.peers.get(&peer) {
+                Some(sender) => sender.send(datagram.clone()).is_ok(),
+                None => false,
+            };
+            if delivered {
+                continue;
+            }
+
+            // Either a new peer or one whose connection has been dropped
+            self.peers.remove(&peer);
+            if amount >= 2 && buffer[1] == PACKET_CONNECT {
+                let (sender, receiver) = mpsc::channel();
+                sender.send(datagram).expect("Receiver was just created");
+                self.peers.insert(peer, sender);
+                let mut connection =
+                    UdpConnection::new(self.socket.clone(), peer, Inbox::Channel(receiver));
+                connection.set_simulated_loss(self.simulated_loss);
+                self.new_connections.push_back(connection);
+            }
+        }
+    }
+}
+
+impl Listener for UdpListener {
+    fn accept(&mut self) -> io::Result<Option<Box<dyn Connection>>> {
+        self.dispatch()?;
+        Ok(self.new_connections.pop_front().map(|c| Box::new(c) as Box<dyn Connection>))
+    }
+
+    fn raw_source(&self) -> RawSource {
+        raw_source(&*self.socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::ServerMessage;
+    use crate::snapshot::{Snapshot, SnapshotDelta};
+
+    fn connected_pair() -> (UdpConnection, UdpConnection) {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        (UdpConnection::from_socket(a).unwrap(), UdpConnection::from_socket(b).unwrap())
+    }
+
+    /// Pumps both connections until `done` returns true or a timeout passes
+    fn pump(
+        a: &mut UdpConnection,
+        b: &mut UdpConnection,
+        received: &mut Vec<Vec<u8>>,
+        mut done: impl FnMut(&Vec<Vec<u8>>) -> bool,
+    ) {
+        let start = Instant::now();
+        while !done(received) && start.elapsed() < Duration::from_secs(10) {
+            a.fetch_bytes().unwrap();
+            b.fetch_bytes().unwrap();
+            while let Some(message) = b.next_message() {
+                received.push(message.unwrap());
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn reliable_messages_arrive_in_order_despite_loss() {
+        let (mut a, mut b) = connected_pair();
+        a.set_simulated_loss(0.3);
+        b.set_simulated_loss(0.3);
+
+        for i in 0..100u32 {
+            a.send(Delivery::Reliable, &i.to_be_bytes()).unwrap();
+        }
+
+        let mut received = vec!();
+        pump(&mut a, &mut b, &mut received, |r| r.len() >= 100);
+
+        let expected = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect::<Vec<_>>();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn unreliable_messages_are_never_reordered_or_duplicated() {
+        let (mut a, mut b) = connected_pair();
+        a.set_simulated_loss(0.3);
+
+        for i in 0..100u32 {
+            a.send(Delivery::Unreliable, &i.to_be_bytes()).unwrap();
+        }
+        // Marks the end since it can not be lost
+        a.send(Delivery::Reliable, &[]).unwrap();
+
+        let mut received = vec!();
+        pump(&mut a, &mut b, &mut received, |r| r.last().map(|m| m.is_empty()).unwrap_or(false));
+
+        let numbers = received[..received.len() - 1].iter()
+            .map(|m| u32::from_be_bytes([m[0], m[1], m[2], m[3]]))
+            .collect::<Vec<_>>();
+        assert!(numbers.len() < 100);
+        assert!(numbers.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn messages_larger_than_a_datagram_are_fragmented() {
+        let (mut a, mut b) = connected_pair();
+        a.set_simulated_loss(0.1);
+        b.set_simulated_loss(0.1);
+
+        let large = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        a.send(Delivery::Reliable, &large).unwrap();
+        a.send(Delivery::Reliable, b"after").unwrap();
+
+        let mut received = vec!();
+        pump(&mut a, &mut b, &mut received, |r| r.len() >= 2);
+        assert_eq!(received, vec!(large.clone(), b"after".to_vec()));
+
+        // Unreliable fragments are not resent, so they have to arrive intact
+        a.set_simulated_loss(0.);
+        a.send(Delivery::Unreliable, &large[..100_000]).unwrap();
+        let mut received = vec!();
+        pump(&mut a, &mut b, &mut received, |r| !r.is_empty());
+        assert_eq!(received, vec!(large[..100_000].to_vec()));
+    }
+
+    #[test]
+    fn listener_accepts_connecting_clients() {
+        let mut listener = UdpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client_thread = std::thread::spawn(move || {
+            let mut client = UdpConnection::connect(address).unwrap();
+            client.send(Delivery::Reliable, b"hello").unwrap();
+            let start = Instant::now();
+            loop {
+                client.fetch_bytes().unwrap();
+                if let Some(message) = client.next_message() {
+                    break message.unwrap();
+                }
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        let start = Instant::now();
+        let mut server_side = loop {
+            if let Some(connection) = listener.accept().unwrap() {
+                break connection;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        server_side.send(Delivery::Reliable, b"welcome").unwrap();
+
+        let start = Instant::now();
+        let message = loop {
+            listener.accept().unwrap();
+            server_side.fetch_bytes().unwrap();
+            if let Some(message) = server_side.next_message() {
+                break message.unwrap();
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        };
+
+        assert_eq!(message, b"hello");
+        assert_eq!(client_thread.join().unwrap(), b"welcome");
+    }
+
+    fn tcp_pair() -> (TcpConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        (TcpConnection::new(stream), peer)
+    }
+
+    #[test]
+    fn clients_may_speak_first() {
+        let mut listener = UdpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client_thread = std::thread::spawn(move || {
+            let mut client = UdpConnection::connect(address).unwrap();
+            client.send(Delivery::Reliable, b"hello").unwrap();
+            let start = Instant::now();
+            loop {
+                client.fetch_bytes().unwrap();
+                if let Some(message) = client.next_message() {
+                    break message.unwrap();
+                }
+                assert!(start.elapsed() < Duration::from_secs(10));
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        });
+
+        // Like the server, which waits for the client to introduce itself
+        let start = Instant::now();
+        let mut server_side = None;
+        let message = loop {
+            if let Some(connection) = listener.accept().unwrap() {
+                server_side = Some(connection);
+            }
+            if let Some(connection) = &mut server_side {
+                connection.fetch_bytes().unwrap();
+                if let Some(message) = connection.next_message() {
+                    break message.unwrap();
+                }
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(message, b"hello");
+
+        let mut server_side = server_side.unwrap();
+        server_side.send(Delivery::Reliable, b"welcome").unwrap();
+        let start = Instant::now();
+        while !client_thread.is_finished() {
+            listener.accept().unwrap();
+            server_side.fetch_bytes().unwrap();
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(client_thread.join().unwrap(), b"welcome");
+    }
+
+    #[test]
+    fn stale_unreliable_frames_are_dropped_for_slow_peers() {
+        let (mut connection, _peer) = tcp_pair();
+
+        let start = Instant::now();
+        for _ in 0..10_000 {
+            connection.send(Delivery::Unreliable, &[0; 1000]).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(connection.queued_bytes() <= constants::OUTBOUND_HIGH_WATER_MARK);
+    }
+
+    #[test]
+    fn stale_frames_are_only_replaced_by_messages_of_the_same_kind() {
+        let (mut connection, _peer) = tcp_pair();
+
+        // Fill the socket buffer, then keep the queue above the high water mark
+        for _ in 0..10_000 {
+            connection.send(Delivery::Unreliable, &[0; 1000]).unwrap();
+        }
+        for _ in 0..100 {
+            connection.send(Delivery::Reliable, &[0; 1000]).unwrap();
+        }
+
+        let game_state = bincode::serialize(&ServerMessage::GameState {
+            snapshot: SnapshotDelta::new(0, None, &Snapshot::empty()),
+            last_input: 0,
+            tick: 0,
+        }).unwrap();
+        let ping = bincode::serialize(&ServerMessage::Ping).unwrap();
+        connection.send(Delivery::Unreliable, &game_state).unwrap();
+        connection.send(Delivery::Unreliable, &ping).unwrap();
+
+        let queued = connection.outbound.iter()
+            .map(|(_, frame)| frame[messages::FRAME_HEADER_SIZE..].to_vec())
+            .collect::<Vec<_>>();
+        assert!(queued.contains(&game_state));
+        assert!(queued.contains(&ping));
+    }
+
+    #[test]
+    fn peers_which_stop_reading_are_dropped() {
+        let (mut connection, _peer) = tcp_pair();
+
+        let error = (0..100_000)
+            .map(|_| connection.send(Delivery::Reliable, &[0; 1000]))
+            .find(|result| result.is_err());
+        assert!(error.is_some());
+    }
+}
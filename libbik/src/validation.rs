@@ -0,0 +1,248 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::constants;
+use crate::ground::{self, Color, Ground, Terrain, TerrainType};
+use crate::math::Vec2;
+use crate::static_object::StaticObjectKind;
+use crate::track::MapConfig;
+
+/// A mistake in a map. Positions are in map coordinates, indices refer to the
+/// lists in `MapConfig`
+#[derive(Debug, PartialEq)]
+pub enum MapProblem {
+    UnknownColor { color: Color, x: u32, y: u32 },
+    CheckpointOffTrack { index: usize, position: Vec2 },
+    FinishLineOffTrack { position: Vec2 },
+    StartSlotOffTrack { index: usize, position: Vec2 },
+    /// An object players crash into which is standing on the road
+    ObjectOnRoad { index: usize, position: Vec2 },
+    ObjectsOverlap { first: usize, second: usize, position: Vec2 },
+    MissingVariant { index: usize, kind: StaticObjectKind, variant: usize },
+    UnreachablePowerup { index: usize, position: Vec2 },
+    DisallowedPowerup { index: usize, position: Vec2 },
+}
+
+impl fmt::Display for MapProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapProblem::UnknownColor { color, x, y } =>
+                write!(f, "Color {:?} at ({}, {}) is not in the terrain palette", color, x, y),
+            MapProblem::CheckpointOffTrack { index, position } =>
+                write!(f, "Checkpoint {} at {:?} is not on the track", index, position),
+            MapProblem::FinishLineOffTrack { position } =>
+                write!(f, "The finish line at {:?} is not on the track", position),
+            MapProblem::StartSlotOffTrack { index, position } =>
+                write!(f, "Start slot {} at {:?} is not on the track", index, position),
+            MapProblem::ObjectOnRoad { index, position } =>
+                write!(f, "Static object {} at {:?} is standing on the road", index, position),
+            MapProblem::ObjectsOverlap { first, second, position } =>
+                write!(f, "Static objects {} and {} overlap at {:?}", first, second, position),
+            MapProblem::MissingVariant { index, kind, variant } => write!(
+                f,
+                "Static object {} is variant {} of {:?} which only has {} variants",
+                index, variant, kind, kind.variant_count()
+            ),
+            MapProblem::UnreachablePowerup { index, position } =>
+                write!(f, "Powerup {} at {:?} can not be reached from the start", index, position),
+            MapProblem::DisallowedPowerup { index, position } =>
+                write!(f, "Powerup {} at {:?} is not allowed by the map rules", index, position),
+        }
+    }
+}
+
+fn is_track(terrain: &Terrain) -> bool {
+    match terrain.kind {
+        TerrainType::Road | TerrainType::Puddle | TerrainType::PitStop => true,
+        TerrainType::Sand | TerrainType::Obstacle => false,
+    }
+}
+
+fn terrain_at(ground: &Ground, position: Vec2) -> &Terrain {
+    ground.query_terrain(position * constants::MAP_SCALE)
+}
+
+/// Finds the pixels which can be driven to from `start` without passing
+/// through obstacles
+fn reachable_pixels(ground: &Ground, start: Vec2) -> Vec<bool> {
+    let (width, height) = ground.size();
+    let mut reached = vec!(false; width as usize * height as usize);
+    let mut queue = VecDeque::new();
+    if start.x >= 0. && start.y >= 0. && (start.x as u32) < width && (start.y as u32) < height {
+        queue.push_back((start.x as u32, start.y as u32));
+    }
+
+    while let Some((x, y)) = queue.pop_front() {
+        let index = (y * width + x) as usize;
+        if reached[index] || ground.pixel_terrain(x, y).kind == TerrainType::Obstacle {
+            continue;
+        }
+        reached[index] = true;
+        if x > 0 { queue.push_back((x - 1, y)) }
+        if y > 0 { queue.push_back((x, y - 1)) }
+        if x + 1 < width { queue.push_back((x + 1, y)) }
+        if y + 1 < height { queue.push_back((x, y + 1)) }
+    }
+    reached
+}
+
+/// Returns every color in the ground image which is not in the palette
+pub fn validate_ground_image(width: u32, pixels: &[u8], palette: &[Terrain]) -> Vec<MapProblem> {
+    ground::unknown_colors(width, pixels, palette)
+        .into_iter()
+        .map(|(color, x, y)| MapProblem::UnknownColor { color, x, y })
+        .collect()
+}
+
+/// Checks the placement of everything in the map against its ground
+pub fn validate_map(config: &MapConfig, ground: &Ground) -> Vec<MapProblem> {
+    let mut problems = vec!();
+
+    for (index, gate) in config.checkpoints.iter().enumerate() {
+        let position = gate.midpoint();
+        if !is_track(terrain_at(ground, position)) {
+            problems.push(MapProblem::CheckpointOffTrack { index, position });
+        }
+    }
+    let finish = config.finish_line.midpoint();
+    if !is_track(terrain_at(ground, finish)) {
+        problems.push(MapProblem::FinishLineOffTrack { position: finish });
+    }
+    for (index, slot) in config.start_grid.iter().enumerate() {
+        if !is_track(terrain_at(ground, slot.position)) {
+            problems.push(MapProblem::StartSlotOffTrack { index, position: slot.position });
+        }
+    }
+
+    for (index, object) in config.static_objects.iter().enumerate() {
+        if object.variant >= object.kind.variant_count() {
+            problems.push(MapProblem::MissingVariant {
+                index,
+                kind: object.kind,
+                variant: object.variant,
+            });
+        }
+        let radius = match object.collision_radius() {
+            Some(radius) => radius,
+            None => continue,
+        };
+        if terrain_at(ground, object.position).kind == TerrainType::Road {
+            problems.push(MapProblem::ObjectOnRoad { index, position: object.position });
+        }
+        for (other_index, other) in config.static_objects.iter().enumerate().skip(index + 1) {
+            if let Some(other_radius) = other.collision_radius() {
+                let distance = object.position.distance_to(other.position) * constants::MAP_SCALE;
+                if distance < (radius + other_radius) * constants::STATIC_OBJECT_SCALE {
+                    problems.push(MapProblem::ObjectsOverlap {
+                        first: index,
+                        second: other_index,
+                        position: object.position,
+                    });
+                }
+            }
+        }
+    }
+
+    let start = config.start_grid.first()
+        .map(|slot| slot.position)
+        .unwrap_or(finish);
+    let reached = reachable_pixels(ground, start);
+    let (width, height) = ground.size();
+    for (index, powerup) in config.powerups.iter().enumerate() {
+        let position = powerup.position;
+        // The ground continues as sand outside the image
+        let inside = position.x >= 0. && position.y >= 0.
+            && (position.x as u32) < width && (position.y as u32) < height;
+        if inside && !reached[(position.y as u32 * width + position.x as u32) as usize] {
+            problems.push(MapProblem::UnreachablePowerup { index, position });
+        }
+        if !config.allows_powerup(&powerup.kind) {
+            problems.push(MapProblem::DisallowedPowerup { index, position });
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::{vec2, LineSegment};
+    use crate::powerup::{Powerup, PowerupKind, PowerupType};
+    use crate::static_object::StaticObject;
+
+    fn default_map() -> (MapConfig, Ground) {
+        let config: MapConfig = ron::de::from_str(
+            &std::fs::read_to_string("../resources/maps/default/map.ron").unwrap()
+        ).unwrap();
+        let ground = Ground::from_png("../resources/maps/default/track.png", config.terrain.clone())
+            .unwrap();
+        (config, ground)
+    }
+
+    #[test]
+    fn default_map_is_valid() {
+        let (config, ground) = default_map();
+        assert_eq!(validate_map(&config, &ground), vec!());
+    }
+
+    #[test]
+    fn misplaced_things_are_reported() {
+        let (mut config, ground) = default_map();
+        let sand = vec2(10., 10.);
+        let road = config.start_grid[0].position;
+        config.checkpoints = vec!(LineSegment::new(sand, sand + vec2(10., 0.)));
+        config.static_objects = vec!(
+            StaticObject { position: road, kind: StaticObjectKind::Tire, variant: 1 },
+            StaticObject { position: road, kind: StaticObjectKind::Barrel, variant: 0 },
+        );
+        config.allowed_powerups = Some(vec!(PowerupType::Nitro));
+        config.powerups = vec!(Powerup {
+            position: sand,
+            kind: PowerupKind::Weapon(crate::powerup::Weapon::Mace),
+            timeout: 0.,
+        });
+
+        assert_eq!(validate_map(&config, &ground), vec!(
+            MapProblem::CheckpointOffTrack { index: 0, position: sand + vec2(5., 0.) },
+            MapProblem::MissingVariant { index: 0, kind: StaticObjectKind::Tire, variant: 1 },
+            MapProblem::ObjectOnRoad { index: 0, position: road },
+            MapProblem::ObjectsOverlap { first: 0, second: 1, position: road },
+            MapProblem::ObjectOnRoad { index: 1, position: road },
+            MapProblem::DisallowedPowerup { index: 0, position: sand },
+        ));
+    }
+
+    #[test]
+    fn enclosed_powerups_are_reported() {
+        let (mut config, _) = default_map();
+        let color = |kind| config.terrain.iter().find(|t| t.kind == kind).unwrap().color;
+        let (road, obstacle) = (color(TerrainType::Road), color(TerrainType::Obstacle));
+        // A road with a ring of obstacles around (30, 30)
+        let size = 40;
+        let mut pixels = vec!();
+        for y in 0..size {
+            for x in 0..size {
+                let ring = (25..=35).contains(&x) && (25..=35).contains(&y)
+                    && !((26..=34).contains(&x) && (26..=34).contains(&y));
+                let color = if ring { obstacle } else { road };
+                pixels.extend_from_slice(&[color.0, color.1, color.2]);
+            }
+        }
+        let ground = Ground::new(size, size, &pixels, config.terrain.clone()).unwrap();
+
+        let start = vec2(5., 5.);
+        config.start_grid.truncate(1);
+        config.start_grid[0].position = start;
+        config.finish_line = LineSegment::new(start, start + vec2(10., 0.));
+        config.checkpoints = vec!();
+        config.static_objects = vec!();
+        config.allowed_powerups = None;
+        let powerup = |position| Powerup { position, kind: PowerupKind::Nitro(3.), timeout: 0. };
+        config.powerups = vec!(powerup(vec2(15., 30.)), powerup(vec2(30., 30.)));
+
+        assert_eq!(validate_map(&config, &ground), vec!(
+            MapProblem::UnreachablePowerup { index: 1, position: vec2(30., 30.) },
+        ));
+    }
+}
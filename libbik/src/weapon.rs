@@ -2,7 +2,7 @@ use serde_derive::{Serialize, Deserialize};
 use crate::powerup;
 use crate::constants;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Mace {
     pub angle: f32,
     durability: f32,
@@ -17,7 +17,7 @@ impl Default for Mace {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum Weapon {
     Mace(Mace),
 }
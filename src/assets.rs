@@ -5,6 +5,17 @@ use sdl2::render::{Texture, TextureCreator};
 use sdl2::video::WindowContext;
 
 use libbik::constants;
+use libbik::static_object::StaticObjectKind;
+use libbik::track;
+
+/// Textures which are different for each map
+pub struct MapAssets<'r> {
+    pub name: String,
+    pub revision: u64,
+    pub track: Texture<'r>,
+    pub track_overlay: Texture<'r>,
+    pub track_overlay_overhead: Texture<'r>,
+}
 
 pub struct Assets<'ttf, 'r> {
     pub font: sdl2::ttf::Font<'ttf, 'r>,
@@ -17,9 +28,8 @@ pub struct Assets<'ttf, 'r> {
     pub more_falling: Texture<'r>,
     pub crashed: Texture<'r>,
     pub driver: Texture<'r>,
-    pub track: Texture<'r>,
-    pub track_overlay: Texture<'r>,
-    pub track_overlay_overhead: Texture<'r>,
+    /// Set once the server has told us which map to play on
+    pub map: Option<MapAssets<'r>>,
     pub arrow: Texture<'r>,
 
     pub red_outline: Texture<'r>,
@@ -31,6 +41,7 @@ pub struct Assets<'ttf, 'r> {
 
     pub mace_pickup: Texture<'r>,
     pub nitro_pickup: Texture<'r>,
+    pub wrench: Texture<'r>,
     pub nitro_sound: Chunk,
     pub hit_sound: Chunk,
 
@@ -76,23 +87,22 @@ impl<'ttf, 'r> Assets<'ttf, 'r> {
             arrow: load_tex("resources/arrow.png"),
 
             driver: load_tex("resources/driver.png"),
-            track: load_tex("resources/track.png"),
-            track_overlay: load_tex("resources/track_overlay.png"),
-            track_overlay_overhead: load_tex("resources/track_overlay_overhead.png"),
+            map: None,
 
             red_outline: load_tex("resources/red_outline.png"),
             finish_line: load_tex("resources/finish_line.png"),
 
-            trees: vec![
-                load_tex("resources/tree1.png"),
-                load_tex("resources/tree2.png"),
-                load_tex("resources/tree3.png"),
-            ],
-            tires: vec![load_tex("resources/tire1.png")],
+            trees: (1..=StaticObjectKind::Tree.variant_count())
+                .map(|i| load_tex(&format!("resources/tree{}.png", i)))
+                .collect(),
+            tires: (1..=StaticObjectKind::Tire.variant_count())
+                .map(|i| load_tex(&format!("resources/tire{}.png", i)))
+                .collect(),
             barrel: load_tex("resources/barrel.png"),
 
             mace_pickup: load_tex("resources/mace.png"),
             nitro_pickup: load_tex("resources/nitro.png"),
+            wrench: load_tex("resources/wrench.png"),
             nitro_sound: Chunk::from_file("resources/audio/nitro.ogg").unwrap(),
             hit_sound: Chunk::from_file("resources/audio/hit.ogg").unwrap(),
 
@@ -106,4 +116,43 @@ impl<'ttf, 'r> Assets<'ttf, 'r> {
 
         assets
     }
+
+    /// Loads the textures of the map folder called `name`, unless they are
+    /// already loaded. `revision` is increased when the files have changed
+    pub fn load_map(
+        &mut self,
+        texture_creator: &'r TextureCreator<WindowContext>,
+        name: &str,
+        revision: u64,
+    ) -> Result<(), String> {
+        if self
+            .map
+            .as_ref()
+            .map(|map| map.name == name && map.revision == revision)
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let load_tex = |file: &str| {
+            let path = track::map_file(name, file)?;
+            let mut tex = texture_creator
+                .load_texture(&path)
+                .map_err(|e| format!("Could not load {}: {}", path.display(), e))?;
+            tex.set_blend_mode(sdl2::render::BlendMode::Blend);
+            Ok::<_, String>(tex)
+        };
+        self.map = Some(MapAssets {
+            name: name.to_string(),
+            revision,
+            track: load_tex("track.png")?,
+            track_overlay: load_tex("track_overlay.png")?,
+            track_overlay_overhead: load_tex("track_overlay_overhead.png")?,
+        });
+        Ok(())
+    }
+
+    pub fn map(&self) -> &MapAssets<'r> {
+        self.map.as_ref().expect("No map has been loaded")
+    }
 }
@@ -1,13 +1,18 @@
 #![allow(unused_imports)]
 mod assets;
 mod client_state;
+mod editor;
+mod interpolation;
 mod menu;
 mod pitch_effect;
+mod prediction;
 mod rendering;
 
+use std::io;
 use std::io::prelude::*;
 use std::net::TcpStream;
-use std::time::Instant;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::{Keycode, Scancode};
@@ -18,13 +23,19 @@ use sdl2::video::Window;
 use structopt::StructOpt;
 
 use assets::Assets;
+use interpolation::Interpolation;
 use libbik::constants;
 use libbik::gamestate;
 use libbik::gamestate::RaceState;
+use libbik::ground::Ground;
 use libbik::math::{vec2, Vec2};
-use libbik::messages::{ClientInput, ClientMessage, MessageReader, ServerMessage, SoundEffect};
+use libbik::messages::{self, ClientInput, ClientMessage, ServerMessage, SoundEffect};
+use libbik::snapshot::{Snapshot, SnapshotDelta, SnapshotHistory, StaticState};
+use libbik::track;
+use libbik::transport::{Connection, TcpConnection, UdpConnection};
 use menu::MenuState;
 use pitch_effect::{start_pitch_effect, PitchEffect};
+use prediction::Prediction;
 
 #[derive(StructOpt)]
 struct Opt {
@@ -33,17 +44,70 @@ struct Opt {
     address: String,
     #[structopt(short, long, default_value = "4444")]
     port: u16,
+    /// Use UDP instead of TCP, the server has to be started with --udp as well
+    #[structopt(long)]
+    udp: bool,
+    /// Fraction of outgoing UDP packets to drop, for testing bad networks
+    #[structopt(long, default_value = "0")]
+    simulated_loss: f32,
+    /// Open the map editor for a map in resources/maps instead of connecting to a server
+    #[structopt(long)]
+    edit: Option<String>,
 }
 
-fn send_client_message(msg: &ClientMessage, stream: &mut TcpStream) {
+fn send_client_message(msg: &ClientMessage, connection: &mut dyn Connection) {
     let data = bincode::serialize(msg).expect("Failed to encode message");
-    let length = data.len() as u16;
-    stream
-        .write_all(&length.to_be_bytes())
-        .expect("Failed to send message length to server");
-    stream
-        .write_all(&data)
-        .expect("Failed to send message to server");
+    // A broken connection is noticed when receiving, so that it can be
+    // reported in one place
+    if let Err(e) = connection.send(msg.delivery(), &data) {
+        println!("Failed to send message to server: {}", e);
+    }
+}
+
+/// Waits until everything that has been sent is written, or acknowledged for
+/// UDP, giving up after CLOSE_FLUSH_TIMEOUT
+fn flush(connection: &mut dyn Connection) {
+    let start = Instant::now();
+    while connection.has_pending_writes()
+        && start.elapsed().as_secs_f32() < constants::CLOSE_FLUSH_TIMEOUT
+    {
+        if connection.fetch_bytes().is_err() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Fetches and decodes everything the server has sent, answering pings on
+/// the way. Fails if the connection is broken or the server has gone quiet
+fn receive_messages(
+    connection: &mut dyn Connection,
+    last_received: &mut Instant,
+) -> Result<Vec<ServerMessage>, String> {
+    connection.fetch_bytes().map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => "The server closed the connection".to_string(),
+        _ => format!("Lost connection to server: {}", e),
+    })?;
+
+    let frames = connection
+        .iter()
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid message from server: {:?}", e))?;
+
+    let mut messages = vec![];
+    for frame in frames {
+        *last_received = Instant::now();
+        match bincode::deserialize(&frame) {
+            Ok(ServerMessage::Ping) => send_client_message(&ClientMessage::Pong, connection),
+            Ok(message) => messages.push(message),
+            Err(_) => return Err("Could not understand a message from the server".to_string()),
+        }
+    }
+
+    if last_received.elapsed().as_secs_f32() > constants::SERVER_TIMEOUT {
+        return Err("Lost connection to server: it stopped responding".to_string());
+    }
+    Ok(messages)
 }
 
 #[derive(PartialEq)]
@@ -55,26 +119,88 @@ enum StateResult {
 struct MainState {
     my_id: u64,
     game_state: gamestate::GameState,
+    /// The game state with other players interpolated, used for drawing
+    render_state: gamestate::GameState,
     client_state: client_state::ClientState,
     last_time: Instant,
+    /// Name of the map folder we are playing on
+    map: String,
+    /// Revision of the map files the ground was loaded from
+    map_revision: u64,
+    ground: Ground,
+    prediction: Prediction,
+    interpolation: Interpolation,
+    received_snapshots: SnapshotHistory,
+    /// The newest snapshot which we have not yet told the server about
+    unacked_snapshot: Option<u64>,
+    /// Time which has passed but not yet been simulated in DELTA_TIME steps
+    input_accumulator: f32,
+    last_received: Instant,
 }
 
 impl MainState {
-    fn new(my_id: u64) -> MainState {
-        MainState {
+    fn new(my_id: u64, map: &str) -> Result<MainState, String> {
+        Ok(MainState {
             my_id,
             game_state: gamestate::GameState::default(),
+            render_state: gamestate::GameState::default(),
             client_state: client_state::ClientState::new(my_id),
             last_time: Instant::now(),
+            map: map.to_string(),
+            map_revision: 0,
+            ground: load_ground(map)?,
+            prediction: Prediction::new(),
+            interpolation: Interpolation::new(),
+            received_snapshots: SnapshotHistory::new(),
+            unacked_snapshot: None,
+            input_accumulator: 0.,
+            last_received: Instant::now(),
+        })
+    }
+
+    fn apply_static_state(&mut self, static_state: StaticState) -> Result<(), String> {
+        if static_state.map != self.map || static_state.map_revision != self.map_revision {
+            println!("Loading map {}", static_state.map);
+            self.ground = load_ground(&static_state.map)?;
+            self.map = static_state.map.clone();
+            self.map_revision = static_state.map_revision;
+        }
+        self.game_state.apply_static_state(static_state);
+        Ok(())
+    }
+
+    /// Reconstructs the game state from a delta. Returns false if the delta
+    /// was relative to a snapshot we no longer have
+    fn receive_snapshot(&mut self, delta: &SnapshotDelta) -> bool {
+        let empty = Snapshot::empty();
+        let base = match delta.base {
+            Some(id) => match self.received_snapshots.get(id) {
+                Some(base) => base,
+                None => return false,
+            },
+            None => &empty,
+        };
+
+        let snapshot = delta.apply(base);
+        self.received_snapshots
+            .push(delta.id, Rc::new(snapshot.clone()));
+        self.game_state.apply_snapshot(snapshot);
+        self.unacked_snapshot = Some(delta.id);
+        true
+    }
+
+    fn acknowledge_snapshot(&mut self, connection: &mut dyn Connection) {
+        if let Some(id) = self.unacked_snapshot.take() {
+            send_client_message(&ClientMessage::AckSnapshot(id), connection);
         }
     }
 
     fn update(
         &mut self,
-        server_reader: &mut MessageReader,
+        server_reader: &mut dyn Connection,
         keyboard_state: &sdl2::keyboard::KeyboardState,
         assets: &mut Assets,
-    ) -> StateResult {
+    ) -> Result<StateResult, String> {
         let elapsed = self.last_time.elapsed();
         self.last_time = Instant::now();
         let dt_duration = std::time::Duration::from_millis(1000 / 60);
@@ -82,20 +208,50 @@ impl MainState {
             std::thread::sleep(dt_duration - elapsed);
         }
 
-        server_reader.fetch_bytes().unwrap();
-
-        for message in server_reader.iter() {
-            match bincode::deserialize(&message).unwrap() {
-                ServerMessage::AssignId(_) => panic!("Got new ID after intialisation"),
-                ServerMessage::GameState(state) => self.game_state = state,
+        for message in receive_messages(server_reader, &mut self.last_received)? {
+            match message {
+                ServerMessage::AssignId { .. } => panic!("Got new ID after intialisation"),
+                ServerMessage::Reconnected(_) => {}
+                ServerMessage::Rejected(reason) => return Err(reason),
+                ServerMessage::Ping => {}
+                ServerMessage::StaticState(static_state) => {
+                    self.apply_static_state(static_state)?
+                }
+                ServerMessage::GameState {
+                    snapshot,
+                    last_input,
+                    tick,
+                } => {
+                    self.game_state.tick = tick;
+                    self.game_state.killfeed.manage_killfeed(tick);
+                    if self.receive_snapshot(&snapshot) {
+                        self.interpolation.add_snapshot(
+                            tick as f32 * constants::DELTA_TIME,
+                            &self.game_state.players,
+                        );
+                        self.prediction.reconcile(
+                            &mut self.game_state,
+                            self.my_id,
+                            last_input,
+                            &self.ground,
+                        );
+                    }
+                }
                 ServerMessage::PlaySound(sound, _pos) => play_sound(match sound {
                     SoundEffect::StartRace => &assets.race_start_sound,
                     SoundEffect::Nitro => &assets.nitro_sound,
                     SoundEffect::Crash => &assets.hit_sound,
                 }),
+                // Crashes are already announced in the killfeed
+                ServerMessage::GameEvent(_) => {}
+                ServerMessage::KillFeed(message) => {
+                    self.game_state.killfeed.receive_message(message)
+                }
             }
         }
 
+        self.acknowledge_snapshot(server_reader);
+
         let mut input = ClientInput::new();
         if keyboard_state.is_scancode_pressed(Scancode::W) {
             input.y_input += 1.0;
@@ -120,36 +276,58 @@ impl MainState {
         self.client_state
             .update(elapsed.as_secs_f32(), &self.game_state, self.my_id);
 
-        let input_message = ClientMessage::Input(input);
-        send_client_message(&input_message, &mut server_reader.stream);
+        // Inputs are sent and predicted in the same fixed steps as the server
+        // simulates them so that they can be replayed exactly
+        self.input_accumulator += elapsed.as_secs_f32();
+        while self.input_accumulator >= constants::DELTA_TIME {
+            self.input_accumulator -= constants::DELTA_TIME;
+
+            Prediction::predict(&mut self.game_state, self.my_id, &input, &self.ground);
+            let sequence = self.prediction.push_input(input.clone());
+            let input_message = ClientMessage::Input {
+                sequence,
+                input: input.clone(),
+            };
+            send_client_message(&input_message, server_reader);
+        }
 
-        StateResult::Continue
+        self.render_state = self.game_state.clone();
+        self.interpolation.apply(&mut self.render_state, self.my_id);
+
+        Ok(StateResult::Continue)
     }
 
     fn draw(&mut self, canvas: &mut Canvas<Window>, assets: &mut Assets) -> Result<(), String> {
         self.client_state
-            .draw(self.my_id, &self.game_state, canvas, assets)
+            .draw(self.my_id, &self.render_state, canvas, assets)
     }
 
     fn draw_ui(&mut self, canvas: &mut Canvas<Window>, assets: &mut Assets) -> Result<(), String> {
         self.client_state
-            .draw_ui(self.my_id, &self.game_state, canvas, assets)?;
+            .draw_ui(self.my_id, &self.render_state, canvas, assets)?;
         Ok(())
     }
 
-    fn get_first_game_state(&mut self, server_reader: &mut MessageReader) {
+    fn get_first_game_state(&mut self, server_reader: &mut dyn Connection) -> Result<(), String> {
         let mut attempts = 0;
 
         loop {
-            server_reader.fetch_bytes().unwrap();
-            for message in server_reader.iter() {
-                match bincode::deserialize(&message).unwrap() {
-                    ServerMessage::GameState(state) => {
-                        self.game_state = state;
+            for message in receive_messages(server_reader, &mut self.last_received)? {
+                match message {
+                    ServerMessage::StaticState(static_state) => {
+                        self.apply_static_state(static_state)?
+                    }
+                    ServerMessage::GameState { snapshot, tick, .. } => {
+                        if !self.receive_snapshot(&snapshot) {
+                            continue;
+                        }
+                        self.game_state.tick = tick;
+                        self.render_state = self.game_state.clone();
                         match self.game_state.get_player_by_id(self.my_id) {
                             // got a game state with out ID in it
                             Some(_) => {
-                                return;
+                                self.acknowledge_snapshot(server_reader);
+                                return Ok(());
                             }
                             _ => {}
                         }
@@ -162,7 +340,7 @@ impl MainState {
             std::thread::sleep(std::time::Duration::from_millis(50));
             attempts += 1;
             if attempts > 20 {
-                panic!("Never recieved game state from server!");
+                return Err("Never received a game state from the server".to_string());
             }
         }
     }
@@ -174,31 +352,182 @@ fn play_sound(chunk: &sdl2::mixer::Chunk) {
     }
 }
 
-pub fn main() -> Result<(), String> {
-    let opts = Opt::from_args();
+/// Loads the ground used to predict our own movement
+fn load_ground(map: &str) -> Result<Ground, String> {
+    track::MapConfig::load(map)?.load_ground(map)
+}
+
+/// A connection to the server which has completed the handshake
+struct Session {
+    connection: Box<dyn Connection>,
+    id: u64,
+    /// Used to take the player back if the connection is lost
+    token: u64,
+    /// Name of the map folder the server is playing on
+    map: String,
+}
+
+/// Connects to the server and performs the handshake, returning a message
+/// explaining why we could not join on failure. With a `session_token` the
+/// player of the lost connection is taken over
+fn connect_to_server(opts: &Opt, session_token: Option<u64>) -> Result<Session, String> {
     let host = format!("{}:{}", opts.address, opts.port);
-    let stream = TcpStream::connect(host).expect("Could not connect to server");
+    let mut reader: Box<dyn Connection> = if opts.udp {
+        let mut connection = UdpConnection::connect(&host)
+            .map_err(|e| format!("Could not connect to {}: {}", host, e))?;
+        connection.set_simulated_loss(opts.simulated_loss);
+        Box::new(connection)
+    } else {
+        let stream = TcpStream::connect(&host)
+            .map_err(|e| format!("Could not connect to {}: {}", host, e))?;
+        stream
+            .set_nonblocking(true)
+            .expect("Could not set socket as nonblocking");
+        Box::new(TcpConnection::new(stream))
+    };
     println!("Connected to server");
 
-    stream
-        .set_nonblocking(true)
-        .expect("Could not set socket as nonblocking");
-    let mut reader = MessageReader::new(stream);
+    send_client_message(
+        &ClientMessage::Hello {
+            protocol_version: messages::PROTOCOL_VERSION,
+            client_build: env!("BIK_BUILD").to_string(),
+        },
+        reader.as_mut(),
+    );
 
+    let start = Instant::now();
     let msg = loop {
-        reader.fetch_bytes().unwrap();
+        reader
+            .fetch_bytes()
+            .map_err(|e| format!("Lost connection to server: {}", e))?;
         if let Some(msg) = reader.iter().next() {
-            break bincode::deserialize(&msg).unwrap();
+            let msg = msg.map_err(|e| format!("Invalid message from server: {:?}", e))?;
+            break bincode::deserialize(&msg).map_err(|_| {
+                "Could not understand the server, it is probably running a different version"
+                    .to_string()
+            })?;
+        }
+        if start.elapsed().as_secs_f32() > constants::HANDSHAKE_TIMEOUT {
+            return Err("The server did not respond".to_string());
         }
+        std::thread::sleep(std::time::Duration::from_millis(10));
     };
 
-    let my_id = if let ServerMessage::AssignId(id) = msg {
-        println!("Received the id {}", id);
-        id
-    } else {
-        panic!("Expected to get an id from server")
+    let (id, new_token, map) = match msg {
+        ServerMessage::AssignId {
+            id,
+            session_token,
+            map,
+        } => (id, session_token, map),
+        ServerMessage::Rejected(reason) => return Err(reason),
+        _ => return Err("Expected to get an id from server".to_string()),
+    };
+    println!("Received the id {}, playing on map {}", id, map);
+    track::MapConfig::load(&map)
+        .map_err(|e| format!("The server is playing on a map we do not have: {}", e))?;
+
+    let session_token = match session_token {
+        Some(session_token) => session_token,
+        None => {
+            return Ok(Session {
+                connection: reader,
+                id,
+                token: new_token,
+                map,
+            })
+        }
     };
 
+    send_client_message(&ClientMessage::Reconnect { session_token }, reader.as_mut());
+    let start = Instant::now();
+    let mut last_received = Instant::now();
+    loop {
+        for message in receive_messages(reader.as_mut(), &mut last_received)? {
+            match message {
+                ServerMessage::Reconnected(Some(id)) => {
+                    println!("Reconnected as {}", id);
+                    return Ok(Session {
+                        connection: reader,
+                        id,
+                        token: session_token,
+                        map,
+                    });
+                }
+                ServerMessage::Reconnected(None) => {
+                    return Err("The race you were in is no longer available".to_string())
+                }
+                _ => {}
+            }
+        }
+        if start.elapsed().as_secs_f32() > constants::HANDSHAKE_TIMEOUT {
+            return Err("The server did not respond".to_string());
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+}
+
+/// Tries to connect again with `session_token` until it works or the server
+/// has forgotten about us. Returns `Ok(None)` if the window was closed
+fn reconnect(
+    opts: &Opt,
+    session_token: u64,
+    reason: String,
+    canvas: &mut Canvas<Window>,
+    event_pump: &mut sdl2::EventPump,
+    assets: &Assets,
+) -> Result<Option<Session>, String> {
+    println!("{}, reconnecting", reason);
+    let mut menu_state = MenuState::new();
+    menu_state.error = Some(format!("{}. Reconnecting...", reason));
+
+    let start = Instant::now();
+    loop {
+        for event in event_pump.poll_iter() {
+            if let Event::Quit { .. } = event {
+                return Ok(None);
+            }
+        }
+        rendering::setup_coordinates(canvas)?;
+        menu_state.draw(canvas, assets)?;
+
+        match connect_to_server(opts, Some(session_token)) {
+            Ok(session) => return Ok(Some(session)),
+            Err(e) => {
+                println!("Could not reconnect: {}", e);
+                if start.elapsed().as_secs_f32() > constants::RECONNECT_GRACE_PERIOD {
+                    return Err(reason);
+                }
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+}
+
+/// Shows `message` until the window is closed or a key is pressed
+fn show_error(
+    message: String,
+    canvas: &mut Canvas<Window>,
+    event_pump: &mut sdl2::EventPump,
+    assets: &Assets,
+) -> Result<(), String> {
+    println!("{}", message);
+    let mut menu_state = MenuState::new();
+    menu_state.error = Some(message);
+    loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown { .. } => return Ok(()),
+                _ => {}
+            }
+        }
+        rendering::setup_coordinates(canvas)?;
+        menu_state.draw(canvas, assets)?;
+        std::thread::sleep(std::time::Duration::from_millis(1000 / 60));
+    }
+}
+
+pub fn main() -> Result<(), String> {
+    let opts = Opt::from_args();
     let sdl = sdl2::init().expect("Could not initialize SDL");
     let video_subsystem = sdl.video().expect("Could not initialize SDL video");
 
@@ -240,6 +569,31 @@ pub fn main() -> Result<(), String> {
 
     let mut event_pump = sdl.event_pump().expect("Could not get event pump");
 
+    if let Some(map) = &opts.edit {
+        return match editor::run(
+            map,
+            &mut canvas,
+            &mut event_pump,
+            &texture_creator,
+            &mut assets,
+        ) {
+            Ok(()) => Ok(()),
+            Err(reason) => show_error(reason, &mut canvas, &mut event_pump, &assets),
+        };
+    }
+
+    let Session {
+        connection: mut reader,
+        id: my_id,
+        token: session_token,
+        map,
+    } = match connect_to_server(&opts, None) {
+        Ok(session) => session,
+        Err(reason) => return show_error(reason, &mut canvas, &mut event_pump, &assets),
+    };
+
+    let mut last_received = Instant::now();
+
     'mainloop: loop {
         let menu_state = &mut MenuState::new();
 
@@ -272,8 +626,9 @@ pub fn main() -> Result<(), String> {
             rendering::setup_coordinates(&mut canvas)?;
 
             // Ignore all messages so we don't freeze the server
-            reader.fetch_bytes().unwrap();
-            for _ in reader.iter() {}
+            if let Err(reason) = receive_messages(reader.as_mut(), &mut last_received) {
+                return show_error(reason, &mut canvas, &mut event_pump, &assets);
+            }
 
             menu_state.update();
 
@@ -287,13 +642,23 @@ pub fn main() -> Result<(), String> {
             &ClientMessage::JoinGame {
                 name: menu_state.name.clone(),
             },
-            &mut reader.stream,
+            reader.as_mut(),
         );
 
-        let main_state = &mut MainState::new(my_id);
+        let main_state = &mut match MainState::new(my_id, &map) {
+            Ok(main_state) => main_state,
+            Err(reason) => return show_error(reason, &mut canvas, &mut event_pump, &assets),
+        };
 
         // blocks until the first game state is recieved
-        main_state.get_first_game_state(&mut reader);
+        if let Err(reason) = main_state
+            .get_first_game_state(reader.as_mut())
+            .and_then(|_| {
+                assets.load_map(&texture_creator, &main_state.map, main_state.map_revision)
+            })
+        {
+            return show_error(reason, &mut canvas, &mut event_pump, &assets);
+        }
 
         let create_lowres_target = |(width, height)| {
             texture_creator
@@ -336,15 +701,12 @@ pub fn main() -> Result<(), String> {
                         }
                         Keycode::Return => match main_state.game_state.race_state {
                             RaceState::NotStarted => {
-                                send_client_message(&ClientMessage::StartGame, &mut reader.stream);
+                                send_client_message(&ClientMessage::StartGame, reader.as_mut());
 
                                 println!("Starting game!")
                             }
                             RaceState::Finished => {
-                                send_client_message(
-                                    &ClientMessage::RestartGame,
-                                    &mut reader.stream,
-                                );
+                                send_client_message(&ClientMessage::RestartGame, reader.as_mut());
                                 println!("Restarting game!")
                             }
                             _ => {}
@@ -359,7 +721,61 @@ pub fn main() -> Result<(), String> {
             canvas.set_draw_color(sdl2::pixels::Color::RGB(255, 204, 104));
 
             let state_result =
-                main_state.update(&mut reader, &event_pump.keyboard_state(), &mut assets);
+                match main_state.update(reader.as_mut(), &event_pump.keyboard_state(), &mut assets)
+                {
+                    Ok(state_result) => state_result,
+                    Err(reason) => {
+                        engine_channel.pause();
+                        let reconnected = reconnect(
+                            &opts,
+                            session_token,
+                            reason,
+                            &mut canvas,
+                            &mut event_pump,
+                            &assets,
+                        );
+                        match reconnected {
+                            Ok(Some(session)) => {
+                                reader = session.connection;
+                                let restored = MainState::new(session.id, &session.map)
+                                    .and_then(|restored| {
+                                        *main_state = restored;
+                                        main_state.get_first_game_state(reader.as_mut())
+                                    })
+                                    .and_then(|_| {
+                                        assets.load_map(
+                                            &texture_creator,
+                                            &main_state.map,
+                                            main_state.map_revision,
+                                        )
+                                    });
+                                if let Err(reason) = restored {
+                                    return show_error(
+                                        reason,
+                                        &mut canvas,
+                                        &mut event_pump,
+                                        &assets,
+                                    );
+                                }
+                                continue 'gameloop;
+                            }
+                            Ok(None) => break 'mainloop,
+                            Err(reason) => {
+                                engine_channel.halt();
+                                return show_error(reason, &mut canvas, &mut event_pump, &assets);
+                            }
+                        }
+                    }
+                };
+
+            // The server switches maps when a race is restarted, and reloads
+            // them when their files change
+            if let Err(reason) =
+                assets.load_map(&texture_creator, &main_state.map, main_state.map_revision)
+            {
+                engine_channel.halt();
+                return show_error(reason, &mut canvas, &mut event_pump, &assets);
+            }
 
             let player_fuel = if let Some(player) = main_state.game_state.get_player_by_id(my_id) {
                 player.fuel_level
@@ -413,5 +829,8 @@ pub fn main() -> Result<(), String> {
         }
     }
 
+    send_client_message(&ClientMessage::Leave, reader.as_mut());
+    flush(reader.as_mut());
+
     Ok(())
 }
@@ -65,14 +65,14 @@ impl ClientState {
 
         rendering::draw_uncentered_scaled(
             canvas,
-            &assets.track,
+            &assets.map().track,
             -camera_position,
             vec2(constants::MAP_SCALE, constants::MAP_SCALE),
         )
         .unwrap();
         rendering::draw_uncentered_scaled(
             canvas,
-            &assets.track_overlay,
+            &assets.map().track_overlay,
             -camera_position,
             vec2(constants::MAP_SCALE, constants::MAP_SCALE),
         )
@@ -124,7 +124,7 @@ impl ClientState {
                     )?;
                 }
                 PlayerState::Falling(_, _) => unimplemented!("missing falling state asset"),
-                PlayerState::Crashed(_) => {
+                PlayerState::Crashed(_) | PlayerState::KnockedOut(_) => {
                     rendering::draw_texture_rotated_and_scaled(
                         canvas,
                         &assets.crashed,
@@ -150,7 +150,7 @@ impl ClientState {
 
         rendering::draw_uncentered_scaled(
             canvas,
-            &assets.track_overlay_overhead,
+            &assets.map().track_overlay_overhead,
             -camera_position,
             vec2(constants::MAP_SCALE, constants::MAP_SCALE),
         )
@@ -176,16 +176,26 @@ impl ClientState {
 
         if self.debug_drawing {
             canvas.set_draw_color((255, 0, 0));
-            for checkpoint in &game_state.checkpoints {
-                let x = checkpoint.position.x - camera_position.x - constants::CHECKPOINT_RADIUS;
-                let y = checkpoint.position.y - camera_position.y - constants::CHECKPOINT_RADIUS;
+            let gates = game_state
+                .checkpoints
+                .iter()
+                .map(|checkpoint| &checkpoint.gate)
+                .chain(std::iter::once(&game_state.finish_line));
+            for gate in gates {
+                let p1 = gate.p1 - camera_position;
+                let p2 = gate.p2 - camera_position;
                 canvas
-                    .draw_rect(Rect::new(
-                        x as i32,
-                        y as i32,
-                        (constants::CHECKPOINT_RADIUS * 2.) as u32,
-                        (constants::CHECKPOINT_RADIUS * 2.) as u32,
-                    ))
+                    .draw_line((p1.x as i32, p1.y as i32), (p2.x as i32, p2.y as i32))
+                    .unwrap();
+                // Tick on the side the gate is passed towards
+                let middle = gate.midpoint() - camera_position;
+                let forward = vec2(gate.p1.y - gate.p2.y, gate.p2.x - gate.p1.x).normalize() * 30.;
+                let tick = middle + forward;
+                canvas
+                    .draw_line(
+                        (middle.x as i32, middle.y as i32),
+                        (tick.x as i32, tick.y as i32),
+                    )
                     .unwrap();
             }
 
@@ -248,13 +258,14 @@ impl ClientState {
         canvas: &mut Canvas<Window>,
         assets: &Assets,
         player: &Player,
+        total_laps: usize,
     ) -> Result<(), String> {
         let (screen_w, screen_h) = canvas.logical_size();
         let oscillation_size = constants::LAP_SCALE + (((self.clock * 2.).sin() + 1.) / 2.) * 0.2;
 
         let mut lap_text = format!("Lap {}", player.lap + 1);
         let mut lap_text_color = (255, 255, 255);
-        if player.lap == constants::TOTAL_NUM_LAPS - 1 {
+        if player.lap + 1 == total_laps {
             lap_text = String::from("Final lap!");
             lap_text_color = constants::FINAL_LAP_COLOR;
         }
@@ -307,6 +318,63 @@ impl ClientState {
         Ok(())
     }
 
+    /// Draws the place of the player and the time gaps to the players just
+    /// ahead and behind
+    fn draw_standing(
+        &self,
+        canvas: &mut Canvas<Window>,
+        assets: &Assets,
+        game_state: &GameState,
+        my_id: u64,
+    ) {
+        let (place, total) = match game_state.get_player_standing(my_id) {
+            Some(standing) => standing,
+            None => return,
+        };
+        let (screen_w, screen_h) = canvas.logical_size();
+        let pos = vec2(
+            screen_w as f32 * constants::STANDING_POS_X,
+            screen_h as f32 * constants::STANDING_POS_Y,
+        );
+
+        rendering::draw_text_rotated_and_scaled(
+            canvas,
+            &format!("P{}/{}", place, total),
+            pos,
+            (255, 255, 255).into(),
+            &assets.race_font,
+            0.,
+            vec2(constants::LAP_SCALE, constants::LAP_SCALE),
+        )
+        .unwrap();
+
+        let name = |index: usize| {
+            game_state
+                .get_player_by_id(game_state.standings[index].id)
+                .map(|player| player.name.clone())
+                .unwrap_or_default()
+        };
+        let mut gaps = vec![];
+        if let Some(gap) = game_state.standings[place - 1].gap_ahead {
+            gaps.push(format!("{} +{:.2}", name(place - 2), gap));
+        }
+        if let Some(gap) = game_state.standings.get(place).and_then(|s| s.gap_ahead) {
+            gaps.push(format!("{} -{:.2}", name(place), gap));
+        }
+        for (i, gap) in gaps.iter().enumerate() {
+            rendering::draw_text_rotated_and_scaled(
+                canvas,
+                gap,
+                pos + vec2(0., (i + 2) as f32 * constants::GAP_PADDING),
+                constants::GAP_COLOR.into(),
+                &assets.mono_font,
+                0.,
+                vec2(0.5, 0.5),
+            )
+            .unwrap();
+        }
+    }
+
     pub fn draw_finish_screen(
         &self,
         my_id: u64,
@@ -372,7 +440,7 @@ impl ClientState {
                 vec2(
                     screen_w as f32 * constants::END_TIME_POS_X,
                     screen_h as f32 * constants::END_TIME_POS_Y
-                        + (constants::TOTAL_NUM_LAPS - lap) as f32 * constants::TIME_PADDING,
+                        + (game_state.laps - lap) as f32 * constants::TIME_PADDING,
                 ),
                 color.into(),
             );
@@ -428,8 +496,40 @@ impl ClientState {
                         checkpoint_vec.angle(),
                     );
 
-                    self.draw_lap_info(canvas, assets, player).unwrap();
+                    self.draw_lap_info(canvas, assets, player, game_state.laps)
+                        .unwrap();
+                    self.draw_standing(canvas, assets, game_state, my_id);
                     self.draw_fuel_gauge(player, canvas, screen_center, assets);
+                    self.draw_health_gauge(player, canvas, assets);
+
+                    if let PlayerState::KnockedOut(_) = player.state {
+                        rendering::draw_text_rotated_and_scaled(
+                            canvas,
+                            "Knocked out!",
+                            screen_center,
+                            constants::KNOCKED_OUT_COLOR.into(),
+                            &assets.race_font,
+                            0.,
+                            vec2(constants::LAP_SCALE, constants::LAP_SCALE),
+                        )
+                        .unwrap();
+                    }
+
+                    if player.wrong_way {
+                        rendering::draw_text_rotated_and_scaled(
+                            canvas,
+                            "Wrong way!",
+                            vec2(
+                                screen_w as f32 * 0.5,
+                                screen_h as f32 * constants::WRONG_WAY_POS_Y,
+                            ),
+                            constants::WRONG_WAY_COLOR.into(),
+                            &assets.race_font,
+                            0.,
+                            vec2(constants::LAP_SCALE, constants::LAP_SCALE),
+                        )
+                        .unwrap();
+                    }
 
                     if let Some(kind) = &player.carried_powerup {
                         let asset = powerup_asset(&kind, &assets);
@@ -476,9 +576,34 @@ impl ClientState {
             }
         }
 
+        self.draw_killfeed(game_state, canvas, assets);
+
         Ok(())
     }
 
+    /// Draws the killfeed messages, fading them out before they disappear
+    fn draw_killfeed(&self, game_state: &GameState, canvas: &mut Canvas<Window>, assets: &Assets) {
+        let (screen_w, screen_h) = canvas.logical_size();
+        let pos = vec2(
+            screen_w as f32 * constants::KILLFEED_POS_X,
+            screen_h as f32 * constants::KILLFEED_POS_Y,
+        );
+
+        for (i, message) in game_state.killfeed.get_messages().iter().enumerate() {
+            let time_left = constants::KILLFEED_DURATION - message.age(game_state.tick);
+            let opacity = (time_left / constants::KILLFEED_FADE_TIME).clamp(0., 1.);
+            let (r, g, b) = constants::KILLFEED_COLOR;
+            rendering::draw_text(
+                canvas,
+                &message.message,
+                pos + vec2(0., i as f32 * constants::KILLFEED_PADDING),
+                Color::RGBA(r, g, b, (opacity * 255.) as u8),
+                &assets.font,
+            )
+            .unwrap();
+        }
+    }
+
     fn draw_race_countdown(
         &self,
         canvas: &mut Canvas<Window>,
@@ -585,6 +710,63 @@ impl ClientState {
             .unwrap();
     }
 
+    /// Draws the health of the player next to the fuel gauge, with a wrench
+    /// above it while repairing
+    fn draw_health_gauge(&self, player: &Player, canvas: &mut Canvas<Window>, assets: &Assets) {
+        let (screen_w, screen_h) = canvas.logical_size();
+
+        let gauge_pos_x = (constants::HEALTH_GAUGE_POS_X * (screen_w as f32)) as i32;
+        let gauge_pos_y = (constants::GAUGE_POS_Y * (screen_h as f32)) as i32;
+        let gauge_width = (constants::GAUGE_WIDTH * (screen_w as f32)) as u32;
+
+        let padding = constants::GAUGE_TEXT_POS_PADDING * (screen_h as f32);
+
+        rendering::draw_text(
+            canvas,
+            "Health",
+            vec2(gauge_pos_x as f32 + 30., gauge_pos_y as f32 - padding),
+            (255, 255, 255).into(),
+            &assets.font,
+        )
+        .unwrap();
+
+        if player.repairing {
+            rendering::draw_texture_centered(
+                canvas,
+                &assets.wrench,
+                vec2(
+                    gauge_pos_x as f32 + gauge_width as f32 / 2.,
+                    gauge_pos_y as f32 - padding * 3.,
+                ),
+            )
+            .unwrap();
+        }
+
+        let max_health_bar_height = (constants::GAUGE_HEIGHT * (screen_h as f32)) as i32;
+        let health_bar_height =
+            (max_health_bar_height as f32 * player.health / constants::MAX_HEALTH) as i32;
+
+        canvas.set_draw_color(constants::HEALTH_BAR_COLOR);
+        canvas
+            .fill_rect(Rect::new(
+                gauge_pos_x,
+                gauge_pos_y + (max_health_bar_height - health_bar_height),
+                gauge_width,
+                health_bar_height as u32,
+            ))
+            .unwrap();
+
+        canvas.set_draw_color(constants::GAUGE_BACKGROUND);
+        canvas
+            .draw_rect(Rect::new(
+                gauge_pos_x,
+                gauge_pos_y,
+                gauge_width,
+                max_health_bar_height as u32,
+            ))
+            .unwrap();
+    }
+
     fn draw_weapon(
         &self,
         player: &Player,
@@ -0,0 +1,513 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::mouse::MouseButton;
+use sdl2::render::{Canvas, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+use crate::assets::Assets;
+use crate::client_state::{powerup_asset, static_object_asset};
+use crate::rendering;
+use libbik::constants;
+use libbik::ground::Ground;
+use libbik::math::{vec2, LineSegment, Vec2};
+use libbik::powerup::{self, Powerup, PowerupKind};
+use libbik::static_object::{StaticObject, StaticObjectKind};
+use libbik::track::{MapConfig, StartSlot};
+use libbik::validation;
+
+/// What a left click on an empty spot places
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Tool {
+    Object(StaticObjectKind),
+    Nitro,
+    Mace,
+    Checkpoint,
+    StartSlot,
+}
+
+const TOOLS: [Tool; 7] = [
+    Tool::Object(StaticObjectKind::Tree),
+    Tool::Object(StaticObjectKind::Tire),
+    Tool::Object(StaticObjectKind::Barrel),
+    Tool::Nitro,
+    Tool::Mace,
+    Tool::Checkpoint,
+    Tool::StartSlot,
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum GateEnd {
+    First,
+    Second,
+}
+
+/// Something in the map which can be grabbed with the mouse
+#[derive(Clone, Copy, PartialEq)]
+enum Handle {
+    StaticObject(usize),
+    Powerup(usize),
+    Checkpoint(usize, GateEnd),
+    FinishLine(GateEnd),
+    StartSlot(usize),
+}
+
+fn gate_end(gate: &LineSegment, end: GateEnd) -> Vec2 {
+    match end {
+        GateEnd::First => gate.p1,
+        GateEnd::Second => gate.p2,
+    }
+}
+
+fn gate_end_mut(gate: &mut LineSegment, end: GateEnd) -> &mut Vec2 {
+    match end {
+        GateEnd::First => &mut gate.p1,
+        GateEnd::Second => &mut gate.p2,
+    }
+}
+
+pub struct Editor {
+    name: String,
+    config: MapConfig,
+    ground: Ground,
+    /// World position of the top left corner of the screen
+    camera: Vec2,
+    zoom: f32,
+    tool: Tool,
+    /// Last known position of the mouse on the screen
+    mouse: Vec2,
+    dragging: Option<Handle>,
+    modified: bool,
+    status: String,
+}
+
+impl Editor {
+    pub fn new(name: &str) -> Result<Self, String> {
+        let config = MapConfig::load(name)?;
+        let ground = config.load_ground(name)?;
+        let camera = config
+            .start_grid
+            .first()
+            .map(|slot| slot.position)
+            .unwrap_or_else(|| config.finish_line.midpoint())
+            * constants::MAP_SCALE
+            - vec2(constants::WINDOW_SIZE, constants::WINDOW_SIZE) / 2.;
+        Ok(Self {
+            name: name.to_string(),
+            config,
+            ground,
+            camera,
+            zoom: 1.,
+            tool: TOOLS[0],
+            mouse: vec2(0., 0.),
+            dragging: None,
+            modified: false,
+            status: String::new(),
+        })
+    }
+
+    fn to_screen(&self, position: Vec2) -> Vec2 {
+        (position * constants::MAP_SCALE - self.camera) * self.zoom
+    }
+
+    fn to_map(&self, screen: Vec2) -> Vec2 {
+        (screen / self.zoom + self.camera) / constants::MAP_SCALE
+    }
+
+    fn handles(&self) -> Vec<Handle> {
+        let mut handles = vec![
+            Handle::FinishLine(GateEnd::First),
+            Handle::FinishLine(GateEnd::Second),
+        ];
+        for i in 0..self.config.checkpoints.len() {
+            handles.push(Handle::Checkpoint(i, GateEnd::First));
+            handles.push(Handle::Checkpoint(i, GateEnd::Second));
+        }
+        handles.extend((0..self.config.start_grid.len()).map(Handle::StartSlot));
+        handles.extend((0..self.config.static_objects.len()).map(Handle::StaticObject));
+        handles.extend((0..self.config.powerups.len()).map(Handle::Powerup));
+        handles
+    }
+
+    fn handle_position(&self, handle: Handle) -> Vec2 {
+        match handle {
+            Handle::StaticObject(i) => self.config.static_objects[i].position,
+            Handle::Powerup(i) => self.config.powerups[i].position,
+            Handle::Checkpoint(i, end) => gate_end(&self.config.checkpoints[i], end),
+            Handle::FinishLine(end) => gate_end(&self.config.finish_line, end),
+            Handle::StartSlot(i) => self.config.start_grid[i].position,
+        }
+    }
+
+    fn move_handle(&mut self, handle: Handle, position: Vec2) {
+        match handle {
+            Handle::StaticObject(i) => self.config.static_objects[i].position = position,
+            Handle::Powerup(i) => self.config.powerups[i].position = position,
+            Handle::Checkpoint(i, end) => {
+                *gate_end_mut(&mut self.config.checkpoints[i], end) = position
+            }
+            Handle::FinishLine(end) => *gate_end_mut(&mut self.config.finish_line, end) = position,
+            Handle::StartSlot(i) => self.config.start_grid[i].position = position,
+        }
+        self.modified = true;
+    }
+
+    /// The handle closest to a point on the screen, if it is close enough to grab
+    fn handle_at(&self, screen: Vec2) -> Option<Handle> {
+        self.handles()
+            .into_iter()
+            .map(|handle| {
+                let distance = self
+                    .to_screen(self.handle_position(handle))
+                    .distance_to(screen);
+                (handle, distance)
+            })
+            .filter(|(_, distance)| *distance < constants::EDITOR_HANDLE_RADIUS)
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(handle, _)| handle)
+    }
+
+    fn place(&mut self, position: Vec2) {
+        match self.tool {
+            Tool::Object(kind) => self.config.static_objects.push(StaticObject {
+                position,
+                kind,
+                variant: 0,
+            }),
+            Tool::Nitro | Tool::Mace => {
+                let kind = if self.tool == Tool::Nitro {
+                    PowerupKind::Nitro(3.)
+                } else {
+                    PowerupKind::Weapon(powerup::Weapon::Mace)
+                };
+                self.config.powerups.push(Powerup {
+                    position,
+                    kind,
+                    timeout: 0.,
+                })
+            }
+            Tool::Checkpoint => {
+                // Passed forward when driving upwards, R flips it
+                let half = vec2(constants::EDITOR_GATE_LENGTH / 2., 0.);
+                self.config
+                    .checkpoints
+                    .push(LineSegment::new(position + half, position - half))
+            }
+            Tool::StartSlot => self.config.start_grid.push(StartSlot {
+                position,
+                angle: 0.,
+            }),
+        }
+        self.modified = true;
+    }
+
+    fn delete(&mut self, handle: Handle) {
+        match handle {
+            Handle::StaticObject(i) => {
+                self.config.static_objects.remove(i);
+            }
+            Handle::Powerup(i) => {
+                self.config.powerups.remove(i);
+            }
+            Handle::Checkpoint(i, _) => {
+                self.config.checkpoints.remove(i);
+            }
+            Handle::FinishLine(_) => {
+                self.status = "The finish line can not be deleted".into();
+                return;
+            }
+            Handle::StartSlot(i) => {
+                self.config.start_grid.remove(i);
+            }
+        }
+        self.modified = true;
+    }
+
+    /// Turns start slots and flips the direction gates are passed in
+    fn rotate(&mut self, handle: Handle) {
+        match handle {
+            Handle::Checkpoint(i, _) => {
+                let gate = &mut self.config.checkpoints[i];
+                std::mem::swap(&mut gate.p1, &mut gate.p2);
+            }
+            Handle::FinishLine(_) => {
+                let gate = &mut self.config.finish_line;
+                std::mem::swap(&mut gate.p1, &mut gate.p2);
+            }
+            Handle::StartSlot(i) => self.config.start_grid[i].angle += PI / 4.,
+            Handle::StaticObject(_) | Handle::Powerup(_) => return,
+        }
+        self.modified = true;
+    }
+
+    fn next_variant(&mut self, handle: Handle) {
+        if let Handle::StaticObject(i) = handle {
+            let object = &mut self.config.static_objects[i];
+            object.variant = (object.variant + 1) % object.kind.variant_count();
+            self.modified = true;
+        }
+    }
+
+    fn save(&mut self) {
+        self.status = match self.config.save(&self.name) {
+            Ok(()) => {
+                self.modified = false;
+                let problems = validation::validate_map(&self.config, &self.ground);
+                match problems.first() {
+                    None => format!("Saved {}", self.name),
+                    Some(problem) => format!(
+                        "Saved {} with {} problems, first: {}",
+                        self.name,
+                        problems.len(),
+                        problem
+                    ),
+                }
+            }
+            Err(e) => e,
+        };
+    }
+
+    fn zoom_at(&mut self, screen: Vec2, factor: f32) {
+        let world = screen / self.zoom + self.camera;
+        self.zoom =
+            (self.zoom * factor).clamp(constants::EDITOR_MIN_ZOOM, constants::EDITOR_MAX_ZOOM);
+        self.camera = world - screen / self.zoom;
+    }
+
+    /// Returns false when the editor should be closed
+    fn handle_event(&mut self, event: Event) -> bool {
+        match event {
+            Event::Quit { .. } => return false,
+            Event::KeyDown {
+                keycode: Some(kc),
+                keymod,
+                ..
+            } => {
+                let hovered = self.handle_at(self.mouse);
+                match kc {
+                    Keycode::Escape => return false,
+                    Keycode::S if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => self.save(),
+                    Keycode::Num1 => self.tool = TOOLS[0],
+                    Keycode::Num2 => self.tool = TOOLS[1],
+                    Keycode::Num3 => self.tool = TOOLS[2],
+                    Keycode::Num4 => self.tool = TOOLS[3],
+                    Keycode::Num5 => self.tool = TOOLS[4],
+                    Keycode::Num6 => self.tool = TOOLS[5],
+                    Keycode::Num7 => self.tool = TOOLS[6],
+                    Keycode::R => {
+                        if let Some(handle) = hovered {
+                            self.rotate(handle)
+                        }
+                    }
+                    Keycode::V => {
+                        if let Some(handle) = hovered {
+                            self.next_variant(handle)
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::MouseButtonDown {
+                mouse_btn, x, y, ..
+            } => {
+                let screen = vec2(x as f32, y as f32);
+                match (mouse_btn, self.handle_at(screen)) {
+                    (MouseButton::Left, Some(handle)) => self.dragging = Some(handle),
+                    (MouseButton::Left, None) => self.place(self.to_map(screen)),
+                    (MouseButton::Right, Some(handle)) => self.delete(handle),
+                    _ => {}
+                }
+            }
+            Event::MouseButtonUp {
+                mouse_btn: MouseButton::Left,
+                ..
+            } => self.dragging = None,
+            Event::MouseMotion { x, y, .. } => {
+                self.mouse = vec2(x as f32, y as f32);
+                if let Some(handle) = self.dragging {
+                    self.move_handle(handle, self.to_map(self.mouse));
+                }
+            }
+            Event::MouseWheel { y, .. } => {
+                self.zoom_at(self.mouse, constants::EDITOR_ZOOM_STEP.powi(y));
+            }
+            _ => {}
+        }
+        true
+    }
+}
+
+impl Editor {
+    fn pan(&mut self, keyboard: &sdl2::keyboard::KeyboardState, delta_time: f32) {
+        let mut direction = vec2(0., 0.);
+        if keyboard.is_scancode_pressed(Scancode::Left) {
+            direction.x -= 1.;
+        }
+        if keyboard.is_scancode_pressed(Scancode::Right) {
+            direction.x += 1.;
+        }
+        if keyboard.is_scancode_pressed(Scancode::Up) {
+            direction.y -= 1.;
+        }
+        if keyboard.is_scancode_pressed(Scancode::Down) {
+            direction.y += 1.;
+        }
+        self.camera += direction * constants::EDITOR_PAN_SPEED * delta_time / self.zoom;
+    }
+
+    fn draw_gate(&self, canvas: &mut Canvas<Window>, gate: &LineSegment) -> Result<(), String> {
+        let p1 = self.to_screen(gate.p1);
+        let p2 = self.to_screen(gate.p2);
+        canvas.draw_line(p1.i32_tuple(), p2.i32_tuple())?;
+        // Tick on the side the gate is passed towards
+        let middle = self.to_screen(gate.midpoint());
+        let forward = vec2(p1.y - p2.y, p2.x - p1.x).normalize() * 15.;
+        canvas.draw_line(middle.i32_tuple(), (middle + forward).i32_tuple())
+    }
+
+    fn draw_circle(
+        &self,
+        canvas: &mut Canvas<Window>,
+        center: Vec2,
+        radius: f32,
+    ) -> Result<(), String> {
+        for i in 0..50 {
+            let direction = Vec2::from_direction(PI * 2. * (i as f32 / 50.), radius);
+            canvas.draw_point((center + direction).i32_tuple())?;
+        }
+        Ok(())
+    }
+
+    fn draw(&self, canvas: &mut Canvas<Window>, assets: &Assets) -> Result<(), String> {
+        let map_scale = vec2(constants::MAP_SCALE, constants::MAP_SCALE) * self.zoom;
+        let origin = self.to_screen(vec2(0., 0.));
+        rendering::draw_uncentered_scaled(canvas, &assets.map().track, origin, map_scale)?;
+        rendering::draw_uncentered_scaled(canvas, &assets.map().track_overlay, origin, map_scale)?;
+        rendering::draw_uncentered_scaled(
+            canvas,
+            &assets.map().track_overlay_overhead,
+            origin,
+            map_scale,
+        )?;
+
+        let object_scale = constants::STATIC_OBJECT_SCALE * self.zoom;
+        for object in &self.config.static_objects {
+            let position = self.to_screen(object.position);
+            // Variants which do not exist are shown by the validation on save
+            if object.variant < object.kind.variant_count() {
+                rendering::draw_texture_rotated_and_scaled(
+                    canvas,
+                    static_object_asset(object, assets),
+                    position,
+                    0.,
+                    vec2(object_scale, object_scale),
+                )?;
+            }
+            if let Some(radius) = object.collision_radius() {
+                canvas.set_draw_color((255, 0, 0));
+                self.draw_circle(canvas, position, radius * object_scale)?;
+            }
+        }
+
+        for powerup in &self.config.powerups {
+            let position = self.to_screen(powerup.position);
+            rendering::draw_uncentered_scaled(
+                canvas,
+                powerup_asset(&powerup.kind, assets),
+                position,
+                vec2(self.zoom, self.zoom),
+            )?;
+            canvas.set_draw_color((255, 0, 255));
+            self.draw_circle(canvas, position, constants::POWERUP_DISTANCE * self.zoom)?;
+        }
+
+        for slot in &self.config.start_grid {
+            rendering::draw_texture_rotated_and_scaled(
+                canvas,
+                &assets.red_outline,
+                self.to_screen(slot.position),
+                slot.angle,
+                vec2(constants::BIKE_SCALE, constants::BIKE_SCALE) * self.zoom,
+            )?;
+        }
+
+        canvas.set_draw_color((255, 0, 0));
+        for gate in &self.config.checkpoints {
+            self.draw_gate(canvas, gate)?;
+        }
+        canvas.set_draw_color((255, 255, 255));
+        self.draw_gate(canvas, &self.config.finish_line)?;
+
+        canvas.set_draw_color((0, 0, 255));
+        for handle in self.handles() {
+            let position = self.to_screen(self.handle_position(handle));
+            self.draw_circle(canvas, position, constants::EDITOR_HANDLE_RADIUS)?;
+        }
+
+        let (screen_w, screen_h) = canvas.output_size()?;
+        let title = format!(
+            "{}{} - placing {:?}",
+            self.config.name,
+            if self.modified { "*" } else { "" },
+            self.tool
+        );
+        rendering::draw_text(
+            canvas,
+            &title,
+            vec2(screen_w as f32 / 2., 20.),
+            (255, 255, 255).into(),
+            &assets.font,
+        )?;
+        rendering::draw_text(
+            canvas,
+            "1-7 tool, click place/drag, right click delete, R rotate, V variant, ctrl+S save",
+            vec2(screen_w as f32 / 2., 40.),
+            (255, 255, 255).into(),
+            &assets.font,
+        )?;
+        if !self.status.is_empty() {
+            rendering::draw_text(
+                canvas,
+                &self.status,
+                vec2(screen_w as f32 / 2., screen_h as f32 - 20.),
+                (255, 255, 255).into(),
+                &assets.font,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs the map editor for the map called `name` until the window is closed
+pub fn run<'ttf, 'r>(
+    name: &str,
+    canvas: &mut Canvas<Window>,
+    event_pump: &mut sdl2::EventPump,
+    texture_creator: &'r TextureCreator<WindowContext>,
+    assets: &mut Assets<'ttf, 'r>,
+) -> Result<(), String> {
+    let mut editor = Editor::new(name)?;
+    assets.load_map(texture_creator, name, 0)?;
+
+    let mut last_time = Instant::now();
+    loop {
+        for event in event_pump.poll_iter() {
+            if !editor.handle_event(event) {
+                return Ok(());
+            }
+        }
+
+        let delta_time = last_time.elapsed().as_secs_f32();
+        last_time = Instant::now();
+        editor.pan(&event_pump.keyboard_state(), delta_time);
+
+        canvas.set_draw_color((255, 204, 104));
+        canvas.clear();
+        editor.draw(canvas, assets)?;
+        canvas.present();
+
+        std::thread::sleep(Duration::from_millis(1000 / 60));
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use libbik::constants;
+use libbik::gamestate::GameState;
+use libbik::math::{angle_diff, Vec2};
+use libbik::player::Player;
+
+struct Snapshot {
+    server_time: f32,
+    players: Vec<Player>,
+}
+
+/// Buffers recent game states so that other players can be drawn slightly
+/// in the past, smoothly moving between the positions the server sent
+pub struct Interpolation {
+    snapshots: VecDeque<Snapshot>,
+    /// Estimate of server time minus local time
+    clock_offset: Option<f32>,
+    start_time: Instant,
+}
+
+impl Interpolation {
+    pub fn new() -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            clock_offset: None,
+            start_time: Instant::now(),
+        }
+    }
+
+    fn local_time(&self) -> f32 {
+        self.start_time.elapsed().as_secs_f32()
+    }
+
+    pub fn add_snapshot(&mut self, server_time: f32, players: &[Player]) {
+        if let Some(last) = self.snapshots.back() {
+            if server_time <= last.server_time {
+                return;
+            }
+        }
+
+        // Late packets make the offset look smaller than it is, so we jump
+        // to any larger offset immediately but only drift slowly downwards
+        let offset = server_time - self.local_time();
+        self.clock_offset = Some(match self.clock_offset {
+            Some(old) if offset < old => old + (offset - old) * 0.01,
+            _ => offset,
+        });
+
+        self.snapshots.push_back(Snapshot {
+            server_time,
+            players: players.to_vec(),
+        });
+        while self.snapshots.len() > constants::SNAPSHOT_BUFFER_SIZE {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Replaces all players except `my_id` with their interpolated state
+    pub fn apply(&self, game_state: &mut GameState, my_id: u64) {
+        let offset = match self.clock_offset {
+            Some(offset) => offset,
+            None => return,
+        };
+        let render_time = self.local_time() + offset - constants::INTERPOLATION_DELAY;
+
+        for player in game_state.players.iter_mut().filter(|p| p.id != my_id) {
+            if let Some(interpolated) = self.player_at(player.id, render_time) {
+                player.position = interpolated.position;
+                player.angle = interpolated.angle;
+                player.steering_angle = interpolated.steering_angle;
+            }
+        }
+    }
+
+    fn player_at(&self, id: u64, time: f32) -> Option<Player> {
+        let find = |snapshot: &Snapshot| snapshot.players.iter().find(|p| p.id == id).cloned();
+
+        let mut previous: Option<(f32, Player)> = None;
+        for snapshot in &self.snapshots {
+            let player = match find(snapshot) {
+                Some(player) => player,
+                None => continue,
+            };
+
+            if snapshot.server_time >= time {
+                return Some(match previous {
+                    Some((previous_time, previous_player)) => {
+                        let factor =
+                            (time - previous_time) / (snapshot.server_time - previous_time);
+                        interpolate(&previous_player, &player, factor)
+                    }
+                    None => player,
+                });
+            }
+            previous = Some((snapshot.server_time, player));
+        }
+
+        // No snapshot is new enough, we have to guess where the player went
+        previous.map(|(previous_time, mut player)| {
+            let extrapolation = (time - previous_time).min(constants::MAX_EXTRAPOLATION);
+            player.position += player.velocity * extrapolation;
+            player
+        })
+    }
+}
+
+fn interpolate(from: &Player, to: &Player, factor: f32) -> Player {
+    let lerp = |a: f32, b: f32| a + (b - a) * factor;
+    let lerp_vec = |a: Vec2, b: Vec2| a + (b - a) * factor;
+
+    let mut result = to.clone();
+    result.position = lerp_vec(from.position, to.position);
+    result.angle = from.angle + angle_diff(from.angle, to.angle) * factor;
+    result.steering_angle = lerp(from.steering_angle, to.steering_angle);
+    result
+}
@@ -0,0 +1,61 @@
+use std::process;
+
+use structopt::StructOpt;
+
+use libbik::ground::{self, Ground, GroundError};
+use libbik::track;
+use libbik::validation::{self, MapProblem};
+
+#[derive(StructOpt)]
+#[structopt(name = "bik-maptool")]
+enum Opt {
+    /// Check maps for mistakes such as checkpoints off the track or trees on the road
+    Validate {
+        /// Names of map folders in resources/maps
+        #[structopt(required = true)]
+        maps: Vec<String>,
+    },
+}
+
+/// Loads the map called `name` and lists everything wrong with it
+fn validate(name: &str) -> Result<Vec<MapProblem>, String> {
+    let config = track::MapConfig::load(name)?;
+    let path = track::map_file(name, "track.png")?;
+    let (width, height, pixels) = ground::load_png(&path)
+        .map_err(|e| format!("Could not load {}: {:?}", path.display(), e))?;
+
+    let mut problems = validation::validate_ground_image(width, &pixels, &config.terrain);
+    match Ground::new(width, height, &pixels, config.terrain.clone()) {
+        Ok(ground) => problems.extend(validation::validate_map(&config, &ground)),
+        // Already listed above, the rest can not be checked without a ground
+        Err(GroundError::UnknownColor { .. }) => {}
+        Err(e) => return Err(format!("Could not load {}: {:?}", path.display(), e)),
+    }
+    Ok(problems)
+}
+
+fn main() {
+    let Opt::Validate { maps } = Opt::from_args();
+
+    let mut valid = true;
+    for map in &maps {
+        match validate(map) {
+            Ok(problems) if problems.is_empty() => println!("{}: ok", map),
+            Ok(problems) => {
+                valid = false;
+                println!("{}: {} problems", map, problems.len());
+                for problem in problems {
+                    println!("  {}", problem);
+                }
+            }
+            Err(e) => {
+                valid = false;
+                println!("{}: {}", map, e);
+            }
+        }
+    }
+
+    if !valid {
+        process::exit(1);
+    }
+}
@@ -8,6 +8,8 @@ use libbik::math::vec2;
 
 pub struct MenuState {
     pub name: String,
+    /// Shown instead of the join prompt when we can not play
+    pub error: Option<String>,
     // more menu options...
 }
 
@@ -15,6 +17,7 @@ impl MenuState {
     pub fn new() -> MenuState {
         MenuState {
             name: String::new(),
+            error: None,
             // more menu options...
         }
     }
@@ -40,6 +43,29 @@ impl MenuState {
         rendering::draw_texture(canvas, &text_texture, vec2(nx + 10., ny + 10.) + res_offset)
     }
 
+    fn draw_error(
+        &mut self,
+        canvas: &mut Canvas<Window>,
+        assets: &Assets,
+        error: &str,
+    ) -> Result<(), String> {
+        let (nx, ny) = constants::NAME_POS;
+        let text = assets
+            .font
+            .render(error)
+            .blended_wrapped(
+                (255, 255, 255),
+                constants::WINDOW_SIZE as u32 - nx as u32 * 2,
+            )
+            .expect("Could not render text");
+
+        let texture_creator = canvas.texture_creator();
+        let text_texture = texture_creator.create_texture_from_surface(text).unwrap();
+
+        let res_offset = rendering::calculate_resolution_offset(canvas);
+        rendering::draw_texture(canvas, &text_texture, vec2(nx + 10., ny + 10.) + res_offset)
+    }
+
     pub fn update(&mut self) {
         // update menu state
     }
@@ -49,7 +75,10 @@ impl MenuState {
         canvas.set_draw_color(constants::MENU_BACKGROUND_COLOR);
         canvas.clear();
 
-        self.draw_player_name(canvas, assets)?;
+        match self.error.clone() {
+            Some(error) => self.draw_error(canvas, assets, &error)?,
+            None => self.draw_player_name(canvas, assets)?,
+        }
 
         canvas.present();
         Ok(())
@@ -0,0 +1,64 @@
+use std::collections::VecDeque;
+
+use libbik::constants;
+use libbik::gamestate::GameState;
+use libbik::ground::Ground;
+use libbik::messages::ClientInput;
+
+/// Keeps track of inputs that have been sent to the server but not yet
+/// acknowledged so that the local player can be simulated ahead of the
+/// server and corrected when a new game state arrives
+pub struct Prediction {
+    next_sequence: u64,
+    pending_inputs: VecDeque<(u64, ClientInput)>,
+}
+
+impl Prediction {
+    pub fn new() -> Self {
+        Self {
+            // The server treats 0 as "no input received yet"
+            next_sequence: 1,
+            pending_inputs: VecDeque::new(),
+        }
+    }
+
+    /// Stores the input until the server acknowledges it and returns the
+    /// sequence number it should be sent with
+    pub fn push_input(&mut self, input: ClientInput) -> u64 {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.pending_inputs.push_back((sequence, input));
+        sequence
+    }
+
+    /// Runs a single simulation step for the local player
+    pub fn predict(game_state: &mut GameState, my_id: u64, input: &ClientInput, ground: &Ground) {
+        let race_state = game_state.race_state.clone();
+        if let Some(player) = game_state.players.iter_mut().find(|p| p.id == my_id) {
+            if !player.finished {
+                player.update(input, ground, constants::DELTA_TIME, &race_state);
+            }
+        }
+    }
+
+    /// Drops all inputs the server has processed and replays the rest on
+    /// top of the authoritative state
+    pub fn reconcile(
+        &mut self,
+        game_state: &mut GameState,
+        my_id: u64,
+        last_input: u64,
+        ground: &Ground,
+    ) {
+        while let Some((sequence, _)) = self.pending_inputs.front() {
+            if *sequence > last_input {
+                break;
+            }
+            self.pending_inputs.pop_front();
+        }
+
+        for (_, input) in &self.pending_inputs {
+            Self::predict(game_state, my_id, input, ground);
+        }
+    }
+}
@@ -1,26 +1,30 @@
 #![allow(unused_imports)]
 
+use std::collections::VecDeque;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
 use std::net::TcpListener;
-use std::net::TcpStream;
-use std::time::Instant;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use std::vec;
 
-use sdl2::image::LoadSurface;
-use sdl2::surface::Surface;
 use structopt::StructOpt;
 use unicode_truncate::UnicodeTruncateStr;
 
 use libbik::constants;
 use libbik::gamestate;
 use libbik::gamestate::RaceState;
+use libbik::generator;
 use libbik::ground::Ground;
-use libbik::math::{vec2, LineSegment, Vec2};
-use libbik::messages::{ClientInput, ClientMessage, MessageReader, ServerMessage, SoundEffect};
+use libbik::killfeed;
+use libbik::math::Vec2;
+use libbik::messages::{self, ClientInput, ClientMessage, ServerMessage, SoundEffect};
+use libbik::network::{ConnectionId, NetworkEvent, NetworkThread};
 use libbik::player::Player;
+use libbik::snapshot::{SnapshotDelta, SnapshotHistory};
 use libbik::track;
+use libbik::transport::{Listener, UdpListener};
 
 #[derive(StructOpt)]
 struct Opt {
@@ -30,224 +34,561 @@ struct Opt {
     /// Override the initial countdown timer
     #[structopt(short = "s", long)]
     start_countdown: Option<f32>,
+    /// Use UDP instead of TCP. Clients have to be started with --udp as well
+    #[structopt(long)]
+    udp: bool,
+    /// Fraction of outgoing UDP packets to drop, for testing bad networks
+    #[structopt(long, default_value = "0")]
+    simulated_loss: f32,
+    /// Seconds without any message from a client before it is dropped
+    #[structopt(long)]
+    idle_timeout: Option<f32>,
+    /// Map to start with, the name of a folder in resources/maps
+    #[structopt(long, default_value = "default")]
+    map: String,
+    /// Maps to switch between, in order, when a race is restarted
+    #[structopt(long)]
+    rotation: Vec<String>,
+    /// Play on a track generated from this seed instead of --map. The same
+    /// seed always gives the same track
+    #[structopt(long)]
+    generate_seed: Option<u64>,
 }
 
-fn send_bytes(bytes: &[u8], stream: &mut TcpStream) -> io::Result<()> {
-    let mut start = 0;
-    loop {
-        match stream.write(&bytes[start..bytes.len()]) {
-            Ok(n) => {
-                if n < bytes.len() - start {
-                    start += n;
-                } else {
-                    break Ok(());
-                }
-            }
-            Err(e) => match e.kind() {
-                io::ErrorKind::WouldBlock => continue,
-                io::ErrorKind::Interrupted => continue,
-                _ => return Err(e),
-            },
-        }
-    }
-}
-
-fn send_server_message(msg: &ServerMessage, stream: &mut TcpStream) -> io::Result<()> {
+fn send_server_message(msg: &ServerMessage, network: &NetworkThread, connection: ConnectionId) {
     let data = bincode::serialize(msg).expect("Failed to encode message");
-    let length = data.len() as u16;
-    send_bytes(&length.to_be_bytes(), stream)?;
-    send_bytes(&data, stream)
+    network.send(connection, msg.delivery(), data);
 }
 
 struct Client {
     id: u64,
-    message_reader: MessageReader,
+    session_token: u64,
+    connection: ConnectionId,
+    /// Inputs which have been received but not yet simulated, one is consumed per tick
+    queued_inputs: VecDeque<(u64, ClientInput)>,
+    /// The most recently simulated input, reused if the client falls behind
     input: ClientInput,
+    /// Sequence number of the most recently simulated input
+    last_input: u64,
+    /// Snapshots which may be used as delta bases once acknowledged
+    sent_snapshots: SnapshotHistory,
+    acked_snapshot: Option<u64>,
+    last_received: Instant,
+    last_ping: Instant,
+}
+
+/// The player of a lost connection, kept so that the client can reconnect
+struct LostSession {
+    session_token: u64,
+    player: Player,
+    lost_at: Instant,
+}
+
+/// A connection which has not yet completed the handshake
+struct PendingConnection {
+    connection: ConnectionId,
+    connected_at: Instant,
 }
 
-struct Server<'a> {
-    listener: TcpListener,
+struct Server {
+    network: NetworkThread,
+    pending_connections: Vec<PendingConnection>,
     connections: Vec<Client>,
+    lost_sessions: Vec<LostSession>,
     state: gamestate::GameState,
-    ground: Ground<'a>,
+    /// Name of the current map folder
+    map: String,
+    map_config: track::MapConfig,
+    /// Increased whenever the files of the map are reloaded
+    map_revision: u64,
+    map_watcher: track::MapWatcher,
+    last_map_check: Instant,
+    /// The files of the map have changed during a race and should be
+    /// reloaded once it is over
+    map_changed: bool,
+    ground: Ground,
     next_id: u64,
+    next_snapshot_id: u64,
     last_time: Instant,
+    /// Time which has passed but not yet been simulated in DELTA_TIME steps
+    accumulator: f32,
     opts: Opt,
     has_had_player: bool,
+    restart_game: bool,
     sounds_to_play: Vec<(SoundEffect, Vec2)>,
+    events_to_send: Vec<gamestate::GameEvent>,
+    killfeed_to_send: Vec<killfeed::Message>,
 }
 
-impl<'a> Server<'a> {
+impl Server {
     pub fn new() -> Self {
-        let opts = Opt::from_args();
-
-        let map_config: track::MapConfig = ron::de::from_str(
-            &fs::read_to_string("resources/map.ron").expect("Could not open map.ron"),
-        )
-        .unwrap();
-
-        let listener = TcpListener::bind("0.0.0.0:4444").unwrap();
-
-        listener.set_nonblocking(true).unwrap();
+        let mut opts = Opt::from_args();
+        if let Some(seed) = opts.generate_seed {
+            opts.map = generator::map_name(seed);
+            match generator::ensure_generated(seed) {
+                Ok(true) => println!("Generated map {}", opts.map),
+                Ok(false) => (),
+                Err(e) => panic!("{}", e),
+            }
+        }
 
-        println!("Listening on 0.0.0.0:4444");
+        // Broken maps in the rotation should be found now rather than when
+        // it is their turn
+        for map in &opts.rotation {
+            if let Err(e) = track::MapConfig::load(map) {
+                panic!("{}", e);
+            }
+        }
+        let map_config = track::MapConfig::load(&opts.map).unwrap_or_else(|e| panic!("{}", e));
+        println!("Playing on map {}", opts.map);
+
+        let listener: Box<dyn Listener + Send> = if opts.udp {
+            let mut listener = UdpListener::bind("0.0.0.0:4444").unwrap();
+            listener.set_simulated_loss(opts.simulated_loss);
+            Box::new(listener)
+        } else {
+            let listener = TcpListener::bind("0.0.0.0:4444").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            Box::new(listener)
+        };
+
+        println!(
+            "Listening on 0.0.0.0:4444 ({})",
+            if opts.udp { "UDP" } else { "TCP" }
+        );
 
         Self {
-            listener,
+            network: NetworkThread::spawn(listener),
+            pending_connections: vec![],
             connections: vec![],
+            lost_sessions: vec![],
             next_id: 0,
-            ground: Ground::new(
-                Surface::from_file("resources/track.png").expect("failed to load map data"),
-            )
-            .expect("failed to load ground"),
+            next_snapshot_id: 0,
+            ground: map_config
+                .load_ground(&opts.map)
+                .unwrap_or_else(|e| panic!("{}", e)),
+            map: opts.map.clone(),
+            map_config: map_config.clone(),
+            map_revision: 0,
+            map_watcher: track::MapWatcher::new(&opts.map).unwrap_or_else(|e| panic!("{}", e)),
+            last_map_check: Instant::now(),
+            map_changed: false,
             last_time: Instant::now(),
-            state: gamestate::GameState::new(
-                map_config.powerups.clone(),
-                map_config.start_position * constants::MAP_SCALE,
-                &map_config.checkpoints,
-                map_config.static_objects.clone(),
-            ),
+            accumulator: 0.,
+            state: new_game_state(&map_config),
             opts,
             has_had_player: false,
+            restart_game: false,
             sounds_to_play: vec![],
+            events_to_send: vec![],
+            killfeed_to_send: vec![],
         }
     }
 
     pub fn update(&mut self) {
-        let elapsed = self.last_time.elapsed();
-        let delta_time = constants::DELTA_TIME;
-        let dt_duration = std::time::Duration::from_millis(constants::SERVER_SLEEP_DURATION);
-        if elapsed < dt_duration {
-            std::thread::sleep(dt_duration - elapsed);
+        // Messages are handled as they arrive until it is time for the next step
+        let until_next_step = (constants::DELTA_TIME - self.accumulator).max(0.);
+        let next_step = self.last_time + Duration::from_secs_f32(until_next_step);
+        while let Some(event) = self.network.next_event(next_step) {
+            self.handle_network_event(event);
         }
-        self.last_time = Instant::now();
 
-        let Self {
-            state,
-            sounds_to_play,
-            ..
-        } = self;
-        state.update(delta_time, |sound| sounds_to_play.push(sound));
+        let now = Instant::now();
+        self.accumulator += (now - self.last_time).as_secs_f32();
+        self.last_time = now;
 
-        self.accept_new_connections();
-        let restart_game = self.update_clients(delta_time);
-        if restart_game {
-            let map_config: track::MapConfig = ron::de::from_str(
-                &fs::read_to_string("resources/map.ron").expect("Could not open map.ron"),
-            )
-            .unwrap();
-            let old_players = self.state.players.clone();
-            self.state = gamestate::GameState::new(
-                map_config.powerups.clone(),
-                map_config.start_position * constants::MAP_SCALE,
-                &map_config.checkpoints,
-                map_config.static_objects.clone(),
-            );
+        self.drop_silent_connections();
+        self.check_map_files();
+        if self.restart_game {
+            self.restart_game = false;
+            self.restart();
+        }
 
-            for p in &old_players {
-                let start_distance = -50. * self.state.players.len() as f32;
-                let position = self.state.start_position + vec2(0., start_distance);
-                self.state
-                    .players
-                    .push(Player::new(p.id, p.name.clone(), position));
+        // The simulation always advances in DELTA_TIME steps, however long we
+        // actually waited, so a busy server falls behind instead of slowing
+        // the game down. If it falls too far behind we give up on catching up
+        let mut steps = 0;
+        while self.accumulator >= constants::DELTA_TIME {
+            if steps == constants::MAX_CATCH_UP_STEPS {
+                println!(
+                    "Server is running behind, skipping {:.2} seconds",
+                    self.accumulator
+                );
+                self.accumulator = 0.;
+                break;
             }
+            self.step(constants::DELTA_TIME);
+            self.accumulator -= constants::DELTA_TIME;
+            steps += 1;
+        }
+
+        if steps > 0 {
+            self.send_game_states();
         }
     }
 
-    fn accept_new_connections(&mut self) {
-        // Read data from clients
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(mut stream) => {
-                    stream.set_nonblocking(true).unwrap();
-                    println!("Got new connection {}", self.next_id);
-                    let message = ServerMessage::AssignId(self.next_id);
-                    if send_server_message(&message, &mut stream).is_err() {
-                        println!("Could not send assign id message");
-                        continue;
-                    }
-                    self.connections.push(Client {
-                        id: self.next_id,
-                        message_reader: MessageReader::new(stream),
-                        input: ClientInput::new(),
-                    });
-                    self.has_had_player = true;
-                    self.next_id += 1;
-                }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    // wait until network socket is ready, typically implemented
-                    // via platform-specific APIs such as epoll or IOCP
-                    break;
-                }
-                e => {
-                    e.expect("Socket listener error");
+    /// The map after the current one in the rotation
+    fn next_map(&self) -> String {
+        let rotation = &self.opts.rotation;
+        match rotation.iter().position(|map| *map == self.map) {
+            Some(i) => rotation[(i + 1) % rotation.len()].clone(),
+            None => rotation.first().unwrap_or(&self.map).clone(),
+        }
+    }
+
+    /// Reloads the map if its files have changed, which is postponed until
+    /// the race is over so that nobody drives into a tree that just appeared
+    fn check_map_files(&mut self) {
+        if self.last_map_check.elapsed().as_secs_f32() >= constants::MAP_RELOAD_CHECK_INTERVAL {
+            self.last_map_check = Instant::now();
+            self.map_changed |= self.map_watcher.changed();
+        }
+
+        if self.map_changed && self.state.race_state == RaceState::NotStarted {
+            self.map_changed = false;
+            let reloaded = track::MapConfig::load(&self.map)
+                .and_then(|config| Ok((config.load_ground(&self.map)?, config)));
+            match reloaded {
+                Ok((ground, map_config)) => {
+                    println!("Reloaded map {}", self.map);
+                    self.ground = ground;
+                    self.map_revision += 1;
+                    self.reset_state(&map_config);
                 }
+                // Probably saved halfway, the next change will be picked up
+                Err(e) => println!("Could not reload map {}: {}", self.map, e),
             }
         }
     }
 
-    fn update_clients(&mut self, delta_time: f32) -> bool {
-        let mut restart_game = false;
-        // Send data to clients
-        let mut clients_to_delete = vec![];
-
-        macro_rules! remove_player_on_disconnect {
-            ($op:expr, $id:expr) => {
-                match $op {
-                    Ok(_) => {}
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {
-                            println!("Player {} disconnected", $id);
-                            clients_to_delete.push($id);
-                            break;
-                        }
-                        e => panic!("Unhandled network issue: {:?}", e),
-                    },
-                };
-            };
+    fn restart(&mut self) {
+        let map = self.next_map();
+        match self.load_map(&map) {
+            Ok(map_config) => self.reset_state(&map_config),
+            // A broken map should not take the server down, keep racing on
+            // the current one until it is fixed
+            Err(e) => {
+                println!("Could not load map {}, staying on {}: {}", map, self.map, e);
+                let map_config = self.map_config.clone();
+                self.reset_state(&map_config);
+            }
         }
+    }
 
-        for client in self.connections.iter_mut() {
-            remove_player_on_disconnect!(client.message_reader.fetch_bytes(), client.id);
+    /// Loads the config of `map`, and its ground if it is not the current
+    /// map or its files have changed. Nothing changes if loading fails
+    fn load_map(&mut self, map: &str) -> Result<track::MapConfig, String> {
+        let map_config = track::MapConfig::load(map)?;
+        if map != self.map || self.map_changed {
+            println!("Loading map {}", map);
+            let ground = map_config.load_ground(map)?;
+            if map != self.map {
+                self.map_watcher = track::MapWatcher::new(map)?;
+            }
+            self.ground = ground;
+            self.map = map.to_string();
+            self.map_revision += 1;
+            self.map_changed = false;
+        }
+        Ok(map_config)
+    }
 
-            for message in client.message_reader.iter() {
-                match bincode::deserialize(&message) {
-                    Ok(ClientMessage::Input(input)) => {
-                        client.input = input;
-                    }
-                    Ok(ClientMessage::JoinGame { mut name }) => {
-                        if name.trim().is_empty() {
-                            name = "Mr Whitespace".into();
-                        } else {
-                            name = name.trim().unicode_truncate(20).0.to_string()
-                        }
+    /// Starts over with a new game state for `map_config`, keeping the players.
+    /// Players of lost sessions start over as well, so that reconnecting
+    /// does not bring back the progress of an earlier race
+    fn reset_state(&mut self, map_config: &track::MapConfig) {
+        let old_players = self.state.players.clone();
+        let tick = self.state.tick;
+        self.state = new_game_state(map_config);
+        self.map_config = map_config.clone();
+        // Clients use the tick as a clock, so it keeps going across races
+        self.state.tick = tick;
+
+        for (i, p) in old_players.iter().enumerate() {
+            let slot = self.state.start_slot(i);
+            self.state
+                .players
+                .push(Player::new(p.id, p.name.clone(), slot.position, slot.angle));
+        }
+        for (i, session) in self.lost_sessions.iter_mut().enumerate() {
+            let slot = self.state.start_slot(old_players.len() + i);
+            let p = &session.player;
+            session.player = Player::new(p.id, p.name.clone(), slot.position, slot.angle);
+        }
 
-                        // this code is duplicated in server.update, sorry it's game jam ok
-                        let start_distance = -50. * self.state.players.len() as f32;
-                        let position = self.state.start_position + vec2(0., start_distance);
+        let message =
+            ServerMessage::StaticState(self.state.static_state(&self.map, self.map_revision));
+        for client in &self.connections {
+            send_server_message(&message, &self.network, client.connection);
+        }
+    }
 
-                        let player = Player::new(client.id, name, position);
-                        self.state.add_player(player);
-                    }
-                    Ok(ClientMessage::StartGame) => {
-                        let countdown = self
-                            .opts
-                            .start_countdown
-                            .unwrap_or(constants::RACE_COUNTDOWN_TIMER_START);
-                        self.state.race_state = RaceState::Starting(countdown);
-                        self.sounds_to_play
-                            .push((SoundEffect::StartRace, self.state.start_position));
-                        println!("Client {} is starting game!", client.id);
-                    }
-                    Ok(ClientMessage::RestartGame) => {
-                        restart_game = true;
+    fn handle_network_event(&mut self, event: NetworkEvent) {
+        match event {
+            NetworkEvent::Connected(connection) => {
+                self.pending_connections.push(PendingConnection {
+                    connection,
+                    connected_at: Instant::now(),
+                });
+            }
+            NetworkEvent::Message(connection, message) => {
+                let pending = self
+                    .pending_connections
+                    .iter()
+                    .position(|pending| pending.connection == connection);
+                if let Some(index) = pending {
+                    let pending = self.pending_connections.remove(index);
+                    self.handle_handshake(pending, &message);
+                } else if let Some(index) = self.client_index(connection) {
+                    self.connections[index].last_received = Instant::now();
+                    match bincode::deserialize(&message) {
+                        Ok(message) => self.handle_client_message(index, message),
+                        Err(_) => {
+                            let id = self.connections[index].id;
+                            println!("Could not decode message from {}, deleting", id);
+                            self.remove_client(index, true);
+                        }
                     }
-                    Err(_) => {
-                        println!("Could not decode message from {}, deleting", client.id);
-                        clients_to_delete.push(client.id);
+                }
+            }
+            NetworkEvent::Disconnected(connection, e) => {
+                self.pending_connections
+                    .retain(|pending| pending.connection != connection);
+                if let Some(index) = self.client_index(connection) {
+                    let id = self.connections[index].id;
+                    match e.kind() {
+                        // Clients which quit without saying `Leave`, for
+                        // example because they crashed, close the stream
+                        io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::UnexpectedEof => {
+                            println!("Player {} disconnected", id);
+                        }
+                        io::ErrorKind::InvalidData => {
+                            println!("Invalid frame for player {}: {}, dropping", id, e);
+                        }
+                        _ => println!("Network error for player {}: {:?}, dropping", id, e),
                     }
+                    self.remove_client(index, true);
+                }
+            }
+        }
+    }
+
+    fn client_index(&self, connection: ConnectionId) -> Option<usize> {
+        self.connections
+            .iter()
+            .position(|client| client.connection == connection)
+    }
+
+    /// Turns a pending connection into a client if its first message is a
+    /// hello with a compatible protocol version
+    fn handle_handshake(&mut self, pending: PendingConnection, message: &[u8]) {
+        let rejection = match bincode::deserialize(message) {
+            Ok(ClientMessage::Hello {
+                protocol_version,
+                client_build,
+            }) => {
+                if protocol_version == messages::PROTOCOL_VERSION {
+                    println!(
+                        "Got new connection {} running build {}",
+                        self.next_id, client_build
+                    );
+                    None
+                } else {
+                    Some(format!(
+                        "The server uses protocol version {} but your client (build {}) \
+                         uses version {}. Please update to the same version as the server",
+                        messages::PROTOCOL_VERSION,
+                        client_build,
+                        protocol_version
+                    ))
                 }
             }
+            _ => Some(
+                "Your client is too old for this server. Please update to the same \
+                 version as the server"
+                    .to_string(),
+            ),
+        };
+
+        if let Some(reason) = rejection {
+            println!("Rejecting connection: {}", reason);
+            let message = ServerMessage::Rejected(reason);
+            send_server_message(&message, &self.network, pending.connection);
+            self.network.close_after_flush(pending.connection);
+            return;
+        }
+
+        let session_token = rand::random();
+        let message = ServerMessage::AssignId {
+            id: self.next_id,
+            session_token,
+            map: self.map.clone(),
+        };
+        send_server_message(&message, &self.network, pending.connection);
+        self.connections.push(Client {
+            id: self.next_id,
+            session_token,
+            connection: pending.connection,
+            queued_inputs: VecDeque::new(),
+            input: ClientInput::new(),
+            last_input: 0,
+            sent_snapshots: SnapshotHistory::new(),
+            acked_snapshot: None,
+            last_received: Instant::now(),
+            last_ping: Instant::now(),
+        });
+        self.has_had_player = true;
+        self.next_id += 1;
+    }
+
+    fn handle_client_message(&mut self, index: usize, message: ClientMessage) {
+        let client = &mut self.connections[index];
+        match message {
+            ClientMessage::Hello { .. } => {
+                println!("Client {} said hello twice, ignoring", client.id);
+            }
+            ClientMessage::Input { sequence, input } => {
+                if sequence > client.last_input {
+                    client.queued_inputs.push_back((sequence, input));
+                }
+                while client.queued_inputs.len() > constants::MAX_QUEUED_INPUTS {
+                    client.queued_inputs.pop_front();
+                }
+            }
+            ClientMessage::JoinGame { mut name } => {
+                if name.trim().is_empty() {
+                    name = "Mr Whitespace".into();
+                } else {
+                    name = name.trim().unicode_truncate(20).0.to_string()
+                }
+
+                let slot = self.state.start_slot(self.state.players.len());
+                let player = Player::new(client.id, name, slot.position, slot.angle);
+                self.state.add_player(player);
+
+                send_server_message(
+                    &ServerMessage::StaticState(
+                        self.state.static_state(&self.map, self.map_revision),
+                    ),
+                    &self.network,
+                    client.connection,
+                );
+            }
+            ClientMessage::StartGame => {
+                let countdown = self
+                    .opts
+                    .start_countdown
+                    .unwrap_or(constants::RACE_COUNTDOWN_TIMER_START);
+                self.state.race_state = RaceState::Starting(countdown);
+                self.sounds_to_play
+                    .push((SoundEffect::StartRace, self.state.finish_line.midpoint()));
+                println!("Client {} is starting game!", client.id);
+            }
+            ClientMessage::RestartGame => {
+                self.restart_game = true;
+            }
+            ClientMessage::AckSnapshot(id) => {
+                if client
+                    .acked_snapshot
+                    .map(|acked| id > acked)
+                    .unwrap_or(true)
+                {
+                    client.acked_snapshot = Some(id);
+                }
+            }
+            ClientMessage::Pong => {}
+            ClientMessage::Leave => {
+                println!("Player {} left", client.id);
+                self.remove_client(index, false);
+            }
+            ClientMessage::Reconnect { session_token } => {
+                let reclaimed = reclaim_session(
+                    &mut self.lost_sessions,
+                    &mut self.state,
+                    client,
+                    session_token,
+                );
+                send_server_message(
+                    &ServerMessage::Reconnected(reclaimed),
+                    &self.network,
+                    client.connection,
+                );
+            }
+        }
+    }
+
+    /// Removes the client and its player. With `keep_session` the player is
+    /// kept around for a while in case the client reconnects
+    fn remove_client(&mut self, index: usize, keep_session: bool) {
+        let client = self.connections.remove(index);
+        self.network.close(client.connection);
+
+        if let Some(player) = self.state.get_player_by_id(client.id) {
+            if keep_session {
+                println!("Keeping player {} around in case it reconnects", client.id);
+                self.lost_sessions.push(LostSession {
+                    session_token: client.session_token,
+                    player: player.clone(),
+                    lost_at: Instant::now(),
+                });
+            }
+        }
+        self.state.players.retain(|player| player.id != client.id);
+
+        if self.has_had_player && self.connections.is_empty() && self.opts.debug_kill {
+            panic!("All clients disconnected and debug mode is on. Exiting");
+        }
+    }
+
+    /// Drops connections which have not said hello or have not sent anything
+    /// for too long
+    fn drop_silent_connections(&mut self) {
+        let network = &self.network;
+        self.pending_connections.retain(|pending| {
+            let waited = pending.connected_at.elapsed().as_secs_f32();
+            if waited < constants::HANDSHAKE_TIMEOUT {
+                true
+            } else {
+                println!("Connection did not say hello in time, dropping");
+                network.close(pending.connection);
+                false
+            }
+        });
+
+        let idle_timeout = self.opts.idle_timeout.unwrap_or(constants::IDLE_TIMEOUT);
+        while let Some(index) = self
+            .connections
+            .iter()
+            .position(|client| client.last_received.elapsed().as_secs_f32() > idle_timeout)
+        {
+            println!("Player {} timed out", self.connections[index].id);
+            self.remove_client(index, true);
+        }
+
+        self.lost_sessions.retain(|session| {
+            session.lost_at.elapsed().as_secs_f32() < constants::RECONNECT_GRACE_PERIOD
+        });
+    }
+
+    /// Simulates one DELTA_TIME step, using one queued input from each client
+    fn step(&mut self, delta_time: f32) {
+        let Self {
+            state,
+            sounds_to_play,
+            ..
+        } = self;
+        state.update(delta_time, |sound| sounds_to_play.push(sound));
+        self.events_to_send.extend(self.state.take_events());
+        self.killfeed_to_send
+            .extend(self.state.killfeed.take_new_messages());
+
+        for client in self.connections.iter_mut() {
+            if let Some((sequence, input)) = client.queued_inputs.pop_front() {
+                client.last_input = sequence;
+                client.input = input;
+            }
 
             for player in &mut self.state.players {
                 if player.id == client.id && !player.finished {
@@ -259,64 +600,119 @@ impl<'a> Server<'a> {
                         &self.state.race_state,
                     );
 
-                    if player.checkpoint < self.state.checkpoints.len() {
-                        let checkpoint = &self.state.checkpoints[player.checkpoint];
-                        if checkpoint.player_reached(player.position) {
-                            player.checkpoint += 1;
-                        }
-                    } else {
-                        // let start_point = self.map_config.start_position * constants::MAP_SCALE;
-                        let start_point = self.state.start_position;
-                        let goal_width = constants::CHECKPOINT_RADIUS * constants::MAP_SCALE;
-                        let goal_line = LineSegment::new(
-                            start_point + vec2(0., -goal_width),
-                            start_point + vec2(0., goal_width),
-                        );
-
-                        let pos_diff = old_pos - player.position;
-                        let behind_old_pos = player.position + pos_diff * 2.;
-                        let player_movement_line =
-                            LineSegment::new(behind_old_pos, player.position);
-                        if player_movement_line.intersects(goal_line) {
-                            player.add_lap();
-                            player.checkpoint = 0;
-                        }
-                    }
+                    player.update_progress(
+                        old_pos,
+                        &self.state.checkpoints,
+                        &self.state.finish_line,
+                        self.state.laps,
+                    );
                     break;
                 }
             }
+        }
+    }
 
-            let result = send_server_message(
-                &ServerMessage::GameState(self.state.clone()),
-                &mut client.message_reader.stream,
+    fn send_game_states(&mut self) {
+        // Built once and shared by all clients, each gets a delta against the
+        // last snapshot it has acknowledged
+        let snapshot = Rc::new(self.state.snapshot());
+        let snapshot_id = self.next_snapshot_id;
+        self.next_snapshot_id += 1;
+
+        for client in self.connections.iter_mut() {
+            if client.last_ping.elapsed().as_secs_f32() >= constants::PING_INTERVAL {
+                client.last_ping = Instant::now();
+                send_server_message(&ServerMessage::Ping, &self.network, client.connection);
+            }
+
+            let base = client
+                .acked_snapshot
+                .and_then(|id| client.sent_snapshots.get(id).map(|base| (id, base)));
+            let delta = SnapshotDelta::new(snapshot_id, base, &snapshot);
+            client.sent_snapshots.push(snapshot_id, snapshot.clone());
+
+            send_server_message(
+                &ServerMessage::GameState {
+                    snapshot: delta,
+                    last_input: client.last_input,
+                    tick: self.state.tick,
+                },
+                &self.network,
+                client.connection,
             );
-            remove_player_on_disconnect!(result, client.id);
         }
 
         for (sound, pos) in self.sounds_to_play.drain(..) {
-            for client in self.connections.iter_mut() {
-                let result = send_server_message(
+            for client in &self.connections {
+                send_server_message(
                     &ServerMessage::PlaySound(sound, pos),
-                    &mut client.message_reader.stream,
+                    &self.network,
+                    client.connection,
                 );
-                remove_player_on_disconnect!(result, client.id);
             }
         }
 
-        self.state
-            .players
-            .retain(|player| !clients_to_delete.contains(&player.id));
-        self.connections
-            .retain(|client| !clients_to_delete.contains(&client.id));
-
-        if self.has_had_player && self.connections.is_empty() && self.opts.debug_kill {
-            panic!("All clients disconnected and debug mode is on. Exiting");
+        for event in self.events_to_send.drain(..) {
+            for client in &self.connections {
+                send_server_message(
+                    &ServerMessage::GameEvent(event.clone()),
+                    &self.network,
+                    client.connection,
+                );
+            }
         }
 
-        restart_game
+        for message in self.killfeed_to_send.drain(..) {
+            for client in &self.connections {
+                send_server_message(
+                    &ServerMessage::KillFeed(message.clone()),
+                    &self.network,
+                    client.connection,
+                );
+            }
+        }
     }
 }
 
+fn new_game_state(map_config: &track::MapConfig) -> gamestate::GameState {
+    gamestate::GameState::new(
+        map_config.allowed_powerups(),
+        map_config.start_grid.clone(),
+        map_config.finish_line.clone(),
+        &map_config.checkpoints,
+        map_config.static_objects.clone(),
+        map_config.laps,
+        map_config.time_limit,
+    )
+}
+
+/// Gives `client` the player of the lost session with `session_token`,
+/// returning the id of that player
+fn reclaim_session(
+    lost_sessions: &mut Vec<LostSession>,
+    state: &mut gamestate::GameState,
+    client: &mut Client,
+    session_token: u64,
+) -> Option<u64> {
+    let index = lost_sessions
+        .iter()
+        .position(|session| session.session_token == session_token)?;
+    let session = lost_sessions.remove(index);
+
+    println!(
+        "Client {} reconnected as player {}",
+        client.id, session.player.id
+    );
+    // The client may already have joined under its new id
+    state
+        .players
+        .retain(|player| player.id != client.id && player.id != session.player.id);
+    client.id = session.player.id;
+    client.session_token = session_token;
+    state.add_player(session.player);
+    Some(client.id)
+}
+
 fn main() {
     let mut server = Server::new();
     loop {